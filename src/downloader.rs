@@ -0,0 +1,2393 @@
+//! Running yt-dlp for one or more URLs and reporting its progress.
+
+use anyhow::{Context, Result};
+use filetime::FileTime;
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
+use log::{error, info, warn};
+use regex::Regex;
+use serde::Serialize;
+use serde_json::Value;
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, BufRead, BufReader, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, ExitStatus, Stdio};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, OnceLock};
+use std::thread;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use url::Url;
+
+/// User agent passed to yt-dlp when `--user-agent` is not given.
+const DEFAULT_USER_AGENT: &str =
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:91.0) Gecko/20100101 Firefox/91.0";
+
+/// Current Chrome user agent, selected with `--user-agent chrome`.
+const CHROME_USER_AGENT: &str = "Mozilla/5.0 (Windows NT 10.0; Win64; x64) AppleWebKit/537.36 \
+     (KHTML, like Gecko) Chrome/129.0.0.0 Safari/537.36";
+
+/// Current Firefox user agent, selected with `--user-agent firefox`.
+const FIREFOX_USER_AGENT: &str =
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:131.0) Gecko/20100101 Firefox/131.0";
+
+/// Browser headers sent along with the default user agent unless `--no-default-headers`
+/// is given.
+const DEFAULT_HEADERS: &[(&str, &str)] = &[
+    (
+        "Accept",
+        "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,*/*;q=0.8",
+    ),
+    ("Accept-Language", "en-US,en;q=0.5"),
+    ("Accept-Encoding", "gzip, deflate, br"),
+    ("Connection", "keep-alive"),
+    ("Upgrade-Insecure-Requests", "1"),
+];
+
+/// Phrases in yt-dlp error output that mean retrying the download cannot succeed.
+const FATAL_ERROR_PATTERNS: &[&str] = &[
+    "video unavailable",
+    "private video",
+    "this video is not available",
+    "sign in to confirm your age",
+    "this video has been removed",
+    "removed by the uploader",
+    "copyright",
+    "not available in your country",
+    "geo restriction",
+    "is not a valid url",
+    "unsupported url",
+];
+
+/// Phrases in yt-dlp error output for a live stream or premiere that has not started yet.
+/// Retrying a few seconds later won't help, but `--wait-for-video` will.
+const UPCOMING_STREAM_PATTERNS: &[&str] = &["this live event will begin", "premieres in"];
+
+/// Hosts accepted without `--allow-any-host`.
+const SUPPORTED_HOSTS: &[&str] = &[
+    "youtube.com",
+    "www.youtube.com",
+    "m.youtube.com",
+    "music.youtube.com",
+    "youtu.be",
+];
+
+/// Prefixes of yt-dlp post-processor output lines, reported as the "postprocess" stage.
+const POSTPROCESS_PREFIXES: &[&str] = &[
+    "[ExtractAudio]",
+    "[EmbedSubtitle]",
+    "[EmbedThumbnail]",
+    "[Metadata]",
+    "[FixupM3u8]",
+    "[FixupM4a]",
+    "[SubtitlesConvertor]",
+    "[ThumbnailsConvertor]",
+    "[VideoConvertor]",
+    "[VideoRemuxer]",
+];
+
+/// Subdirectory of the output directory where yt-dlp keeps partial and intermediate files.
+/// Finished files are moved out of it, so the output directory only holds complete files.
+const TEMP_SUBDIR: &str = ".yt-dlp-temp";
+
+thread_local! {
+    /// URL being downloaded on this thread, included in the log file.
+    static CURRENT_URL: RefCell<Option<String>> = const { RefCell::new(None) };
+}
+
+/// Free space on the output volume below which a warning is logged before downloading.
+const LOW_DISK_SPACE_WARNING: u64 = 1024 * 1024 * 1024;
+
+/// Set by `request_stop`. Once set, no new downloads or retries are started.
+static STOP_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+/// Process ids of the yt-dlp children currently running, so Ctrl-C can interrupt them.
+static RUNNING_CHILDREN: Mutex<Vec<u32>> = Mutex::new(Vec::new());
+
+/// Settings shared by every download in a run, resolved from `Args` in `main`.
+#[derive(Clone)]
+pub struct DownloadOptions {
+    pub yt_dlp_path: PathBuf,
+    pub ffmpeg_path: PathBuf,
+    pub output: PathBuf,
+    pub output_template: String,
+    pub archive: Option<PathBuf>,
+    pub restrict_filenames: bool,
+    pub windows_filenames: bool,
+    pub allow_any_host: bool,
+    pub retry_delay: u64,
+    /// `None` retries transient failures until the download succeeds.
+    pub max_retries: Option<u32>,
+    pub backoff: bool,
+    pub max_backoff: u64,
+    pub timeout: Option<u64>,
+    pub require_free_space: Option<u64>,
+    pub limit_rate: Option<String>,
+    /// Download a live stream from its start instead of from the current point.
+    pub live_from_start: bool,
+    /// Wait for a scheduled stream or premiere, retrying every this many seconds.
+    pub wait_for_video: Option<u64>,
+    /// Number of fragments yt-dlp downloads in parallel; 1 keeps yt-dlp's default.
+    pub concurrent_fragments: u64,
+    pub audio_only: bool,
+    pub audio_format: String,
+    pub container: String,
+    pub quality: String,
+    /// yt-dlp format id picked by the user, used instead of `quality` when set.
+    pub format: Option<String>,
+    /// yt-dlp `--format-sort` fields (e.g. "fps,vcodec:vp9"), which replace the container
+    /// preference of `format_selector` when set.
+    pub format_sort: Option<String>,
+    pub no_playlist: bool,
+    pub playlist_items: Option<String>,
+    pub embed_metadata: bool,
+    /// Save the description and the info JSON next to the video, named like it.
+    pub write_description: bool,
+    pub write_info_json: bool,
+    pub verify: bool,
+    pub no_mtime: bool,
+    pub mtime_now: bool,
+    /// User agent passed to yt-dlp; `None` leaves yt-dlp's own default.
+    pub user_agent: Option<String>,
+    /// Skip the browser headers normally sent with every download.
+    pub no_default_headers: bool,
+    pub proxy: Option<String>,
+    pub subs: bool,
+    pub auto_subs: bool,
+    pub embed_subs: bool,
+    pub sub_langs: String,
+    pub cookies: Option<PathBuf>,
+    pub cookies_from_browser: Option<String>,
+    pub sponsorblock_remove: Option<String>,
+    pub sponsorblock_mark: Option<String>,
+    pub progress_json: bool,
+    /// Hide yt-dlp's own output apart from the progress bar, warnings and errors.
+    pub quiet: bool,
+    /// Pass `--verbose` to yt-dlp.
+    pub verbose: bool,
+    pub dry_run: bool,
+    pub yt_dlp_args: Vec<String>,
+    pub sections: Vec<String>,
+    pub exec: Option<String>,
+    /// Fail the download when the `exec` hook fails instead of only logging it.
+    pub exec_strict: bool,
+    /// Check with `yt-dlp --get-filename` whether the files already exist and skip the
+    /// download if so.
+    pub skip_existing: bool,
+    /// Restart downloads from scratch instead of resuming partial files, deleting the
+    /// URL's stale partial files first.
+    pub no_resume: bool,
+}
+
+/// A file produced by `download_video`.
+#[derive(Serialize, Debug, Clone)]
+pub struct DownloadOutcome {
+    pub path: PathBuf,
+    /// Size of the finished file, or `None` if it could not be read.
+    pub bytes: Option<u64>,
+    /// The file already existed, so yt-dlp did not download it again.
+    pub skipped: bool,
+    /// The file was merged from separate video and audio streams.
+    pub merged: bool,
+    /// ID of the video the file belongs to, if yt-dlp announced one before writing it.
+    #[serde(skip)]
+    pub video_id: Option<String>,
+}
+
+impl DownloadOutcome {
+    fn new(path: PathBuf) -> Self {
+        DownloadOutcome {
+            path,
+            bytes: None,
+            skipped: false,
+            merged: false,
+            video_id: None,
+        }
+    }
+
+    /// Describes how the file was obtained, for the batch summary.
+    pub fn status(&self) -> &'static str {
+        if self.skipped {
+            "skipped"
+        } else if self.merged {
+            "merged"
+        } else {
+            "downloaded"
+        }
+    }
+}
+
+/// Result of downloading a single URL in a batch.
+#[derive(Serialize, Debug)]
+pub struct BatchResult {
+    pub url: String,
+    pub success: bool,
+    pub files: Vec<DownloadOutcome>,
+    pub error: Option<String>,
+    /// Time spent on the URL, including retries.
+    pub elapsed_secs: f64,
+}
+
+/// Error returned by `download_video` when yt-dlp exits unsuccessfully.
+#[derive(Debug)]
+pub enum DownloadError {
+    /// yt-dlp reported a problem that retrying will not fix, such as a private or removed
+    /// video. Holds the offending error line.
+    Fatal(String),
+    /// yt-dlp failed for another reason, which may be transient.
+    Failed(ExitStatus),
+    /// yt-dlp printed no new line for the given number of seconds and was killed.
+    Stalled(u64),
+    /// A downloaded file failed the `--verify` check with ffprobe and was deleted.
+    Unplayable(String),
+    /// The `--exec` hook failed with `--exec-strict` set. The file itself was downloaded,
+    /// so retrying would not help.
+    HookFailed(String),
+    /// The output volume ran low on space before a playlist item, so yt-dlp was stopped.
+    LowDiskSpace(String),
+}
+
+impl DownloadError {
+    pub fn is_retryable(&self) -> bool {
+        matches!(
+            self,
+            DownloadError::Failed(_) | DownloadError::Stalled(_) | DownloadError::Unplayable(_)
+        )
+    }
+}
+
+impl fmt::Display for DownloadError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DownloadError::Fatal(line) => write!(f, "{}", line),
+            DownloadError::Failed(status) => {
+                write!(f, "yt-dlp command failed with status {}", status)
+            }
+            DownloadError::Stalled(secs) => {
+                write!(f, "yt-dlp made no progress for {} seconds", secs)
+            }
+            DownloadError::Unplayable(reason)
+            | DownloadError::HookFailed(reason)
+            | DownloadError::LowDiskSpace(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for DownloadError {}
+
+/// Returns whether `url` is an http or https URL with a host. Schemes such as `file:` or
+/// `ftp:` are rejected even with `--allow-any-host`.
+pub fn is_valid_url(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| {
+        matches!(url.scheme(), "http" | "https") && url.host_str().is_some_and(|h| !h.is_empty())
+    })
+}
+
+/// Returns whether the URL's host is one of the `SUPPORTED_HOSTS`.
+pub fn is_supported_host(url: &str) -> bool {
+    Url::parse(url)
+        .ok()
+        .and_then(|url| url.host_str().map(|host| host.to_lowercase()))
+        .is_some_and(|host| SUPPORTED_HOSTS.contains(&host.as_str()))
+}
+
+/// Returns whether the URL refers to a playlist, either directly or via a `list` parameter.
+pub fn is_playlist_url(url: &str) -> bool {
+    Url::parse(url).is_ok_and(|url| {
+        url.path() == "/playlist" || url.query_pairs().any(|(key, _)| key == "list")
+    })
+}
+
+/// The kind of YouTube page a URL points to, as told by `classify_url`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UrlKind {
+    Video,
+    Playlist,
+    Channel,
+    Shorts,
+    ShortLink,
+    /// Another site, or a YouTube page not recognized here.
+    Unknown,
+}
+
+impl fmt::Display for UrlKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            UrlKind::Video => "video",
+            UrlKind::Playlist => "playlist",
+            UrlKind::Channel => "channel",
+            UrlKind::Shorts => "short",
+            UrlKind::ShortLink => "youtu.be short link",
+            UrlKind::Unknown => "unrecognized page",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Tells from the host and path what kind of YouTube page `url` points to.
+pub fn classify_url(url: &str) -> UrlKind {
+    let parsed = match Url::parse(url) {
+        Ok(parsed) if is_valid_url(url) && is_supported_host(url) => parsed,
+        _ => return UrlKind::Unknown,
+    };
+    if parsed.host_str().is_some_and(|host| host.eq_ignore_ascii_case("youtu.be")) {
+        return UrlKind::ShortLink;
+    }
+    let first = parsed
+        .path_segments()
+        .and_then(|mut segments| segments.next())
+        .unwrap_or("");
+    match first {
+        "watch" => UrlKind::Video,
+        "playlist" => UrlKind::Playlist,
+        "shorts" => UrlKind::Shorts,
+        "channel" | "c" | "user" => UrlKind::Channel,
+        handle if handle.starts_with('@') => UrlKind::Channel,
+        _ => UrlKind::Unknown,
+    }
+}
+
+/// Returns whether `id` looks like a YouTube video id: 11 letters, digits, `-` or `_`.
+fn is_video_id(id: &str) -> bool {
+    id.len() == 11
+        && id
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_')
+}
+
+/// Rewrites a link to a YouTube video, including youtu.be short links and embed, shorts and
+/// live URLs, as `https://www.youtube.com/watch?v=ID`. This drops tracking parameters such
+/// as `si=` and `feature=`, and also `list=` unless `keep_playlist` is set. Playlist pages
+/// and links to other sites are returned unchanged.
+pub fn normalize_url(url: &str, keep_playlist: bool) -> String {
+    let parsed = match Url::parse(url) {
+        Ok(parsed) => parsed,
+        Err(_) => return url.to_string(),
+    };
+    let host = parsed.host_str().unwrap_or("").to_lowercase();
+    let mut segments = parsed
+        .path_segments()
+        .into_iter()
+        .flatten()
+        .filter(|segment| !segment.is_empty());
+    let query = |key: &str| {
+        parsed
+            .query_pairs()
+            .find(|(k, _)| k == key)
+            .map(|(_, value)| value.into_owned())
+    };
+    let id = match host.as_str() {
+        "youtu.be" => segments.next().map(str::to_string),
+        "youtube.com" | "www.youtube.com" | "m.youtube.com" | "music.youtube.com"
+        | "www.youtube-nocookie.com" => match segments.next() {
+            Some("watch") => query("v"),
+            Some("embed" | "shorts" | "live" | "v") => segments.next().map(str::to_string),
+            _ => None,
+        },
+        _ => None,
+    };
+    let id = match id {
+        Some(id) if is_video_id(&id) => id,
+        _ => return url.to_string(),
+    };
+
+    let mut canonical = Url::parse("https://www.youtube.com/watch").unwrap();
+    canonical.query_pairs_mut().append_pair("v", &id);
+    if let Some(list) = query("list").filter(|_| keep_playlist) {
+        canonical.query_pairs_mut().append_pair("list", &list);
+    }
+    canonical.to_string()
+}
+
+/// Checks a URL before any process is spawned for it, explaining why it was rejected.
+pub fn check_url(url: &str, allow_any_host: bool) -> Result<()> {
+    if !is_valid_url(url) {
+        anyhow::bail!("Invalid URL: {}", url);
+    }
+    if !allow_any_host && !is_supported_host(url) {
+        anyhow::bail!(
+            "{} is not a YouTube link (use --allow-any-host for other sites)",
+            url
+        );
+    }
+    Ok(())
+}
+
+/// Builds the yt-dlp format selector for the requested quality. For mp4 and webm, streams
+/// that already fit the container are preferred; mkv takes any streams.
+pub fn format_selector(quality: &str, container: &str) -> String {
+    let (video, audio, single) = match quality {
+        "worst" => ("worstvideo", "worstaudio", "worst"),
+        _ => ("bestvideo", "bestaudio", "best"),
+    };
+    let filter = match quality {
+        "best" | "worst" => String::new(),
+        height => format!("[height<={}]", height),
+    };
+    let any = format!("{video}{filter}+{audio}/{single}{filter}");
+    match container {
+        "mp4" => format!("{video}{filter}[ext=mp4]+{audio}[ext=m4a]/{any}"),
+        "webm" => format!("{video}{filter}[ext=webm]+{audio}[ext=webm]/{any}"),
+        _ => any,
+    }
+}
+
+/// A progress event printed as one line of JSON with `--progress-json`.
+#[derive(Serialize, Debug, Default)]
+struct ProgressEvent<'a> {
+    url: &'a str,
+    stage: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    percent: Option<f64>,
+    /// Bytes downloaded so far, for live streams whose total size is unknown.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    downloaded: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    speed: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    eta: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    item: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    items: Option<u32>,
+}
+
+/// The final event printed for each file or failed URL with `--progress-json`.
+#[derive(Serialize, Debug)]
+struct ResultEvent<'a> {
+    url: &'a str,
+    status: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    file: Option<&'a Path>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    error: Option<&'a str>,
+}
+
+impl ResultEvent<'_> {
+    /// Prints the events for a finished URL: one per downloaded file, or a single event if
+    /// there are no files.
+    fn emit_all(result: &BatchResult) {
+        let files: Vec<Option<&Path>> = if result.files.is_empty() {
+            vec![None]
+        } else {
+            result.files.iter().map(|f| Some(f.path.as_path())).collect()
+        };
+        for file in files {
+            let event = ResultEvent {
+                url: &result.url,
+                status: if result.success { "ok" } else { "error" },
+                file,
+                error: result.error.as_deref(),
+            };
+            if let Ok(json) = serde_json::to_string(&event) {
+                println!("{}", json);
+            }
+        }
+    }
+}
+
+/// Reports the progress of one download, either on a progress bar or, with
+/// `--progress-json`, as JSON events on stdout.
+#[derive(Clone)]
+struct ProgressReporter {
+    pb: ProgressBar,
+    progress: MultiProgress,
+    /// Overall position in a playlist, created when the first playlist item starts.
+    playlist_pb: Arc<OnceLock<ProgressBar>>,
+    /// URL of the download when JSON events are enabled.
+    json_url: Option<String>,
+    /// Set once the download turns out to be a live stream, which has no percentage.
+    live: Arc<AtomicBool>,
+}
+
+impl ProgressReporter {
+    fn new(options: &DownloadOptions, url: &str, progress: &MultiProgress) -> Self {
+        if options.progress_json {
+            return ProgressReporter {
+                pb: ProgressBar::hidden(),
+                progress: progress.clone(),
+                playlist_pb: Arc::new(OnceLock::new()),
+                json_url: Some(url.to_string()),
+                live: Arc::new(AtomicBool::new(false)),
+            };
+        }
+        let pb = progress.add(ProgressBar::new(100));
+        pb.set_style(
+            ProgressStyle::default_bar()
+                .template("{bar:40.cyan/blue} {pos:>3}% {msg}")
+                .unwrap()
+                .progress_chars("##-"),
+        );
+        ProgressReporter {
+            pb,
+            progress: progress.clone(),
+            playlist_pb: Arc::new(OnceLock::new()),
+            json_url: None,
+            live: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Moves the playlist bar to item `item` of `items`, creating it above the file bar
+    /// the first time.
+    fn set_playlist_item(&self, item: u64, items: u64) {
+        if self.json_url.is_some() {
+            return;
+        }
+        let playlist_pb = self.playlist_pb.get_or_init(|| {
+            let playlist_pb = self
+                .progress
+                .insert_before(&self.pb, ProgressBar::new(items));
+            playlist_pb.set_style(
+                ProgressStyle::default_bar()
+                    .template("Playlist {bar:40.green/white} {pos}/{len} items")
+                    .unwrap()
+                    .progress_chars("##-"),
+            );
+            playlist_pb
+        });
+        playlist_pb.set_length(items);
+        playlist_pb.set_position(item.saturating_sub(1));
+    }
+
+    /// Marks the download as complete on every bar.
+    fn finish(&self) {
+        if let Some(playlist_pb) = self.playlist_pb.get() {
+            playlist_pb.set_position(playlist_pb.length().unwrap_or(0));
+            playlist_pb.finish();
+        }
+        self.pb.finish_with_message("Download complete!");
+    }
+
+    /// Prints `event` for this download's URL when JSON events are enabled.
+    fn emit(&self, event: ProgressEvent<'_>) {
+        if let Some(url) = &self.json_url {
+            let event = ProgressEvent { url, ..event };
+            if let Ok(json) = serde_json::to_string(&event) {
+                println!("{}", json);
+            }
+        }
+    }
+
+    /// Updates the progress from a line of yt-dlp output. Percentages drive the file bar,
+    /// playlist "Downloading item N of M" lines drive the playlist bar, and post-processing steps
+    /// are reported as their own stages. Returns `false` if the line should still be
+    /// shown to the user.
+    fn update(&self, line: &str) -> bool {
+        static PERCENT_REGEX: OnceLock<Regex> = OnceLock::new();
+        static LIVE_REGEX: OnceLock<Regex> = OnceLock::new();
+        let percent_regex = PERCENT_REGEX.get_or_init(|| {
+            Regex::new(r"\[download\]\s+(\d+\.\d+)%(?:.*?\bat\s+(\S+))?(?:.*?\bETA\s+(\S+))?")
+                .unwrap()
+        });
+        // Live streams have no total size, so yt-dlp reports "<size> at <speed> (<elapsed>)".
+        let live_regex = LIVE_REGEX.get_or_init(|| {
+            Regex::new(r"^\[download\]\s+(\S+) at\s+(\S+) \((\d+:\d+(?::\d+)?)\)").unwrap()
+        });
+
+        if let Some((item, items)) = playlist_item(line) {
+            self.set_playlist_item(item.into(), items.into());
+            self.pb.set_position(0);
+            // Drop the previous item's speed and ETA until the new item reports its own.
+            self.pb.set_message("");
+            self.emit(ProgressEvent {
+                stage: "playlist",
+                item: Some(item),
+                items: Some(items),
+                ..Default::default()
+            });
+            return true;
+        }
+        if let Some(caps) = live_regex.captures(line) {
+            if !self.live.swap(true, Ordering::SeqCst) {
+                let style = ProgressStyle::default_spinner().template("{spinner} {msg}");
+                self.pb.set_style(style.unwrap());
+            }
+            self.pb.set_message(format!("Live: {} at {} ({})", &caps[1], &caps[2], &caps[3]));
+            self.emit(ProgressEvent {
+                stage: "download",
+                downloaded: Some(&caps[1]),
+                speed: Some(&caps[2]),
+                ..Default::default()
+            });
+            return true;
+        }
+        if let Some(caps) = percent_regex.captures(line) {
+            if let Ok(percent) = caps[1].parse::<f64>() {
+                // Speed and ETA are missing or "Unknown" on some lines, e.g. the final 100% one.
+                fn known(m: Option<regex::Match<'_>>) -> Option<&str> {
+                    m.map(|m| m.as_str()).filter(|s| !s.starts_with("Unknown"))
+                }
+                let speed = known(caps.get(2));
+                let eta = known(caps.get(3));
+                self.pb.set_position(percent.round() as u64);
+                match (speed, eta) {
+                    (Some(speed), Some(eta)) => {
+                        self.pb.set_message(format!("{} / ETA {}", speed, eta))
+                    }
+                    (Some(speed), None) => self.pb.set_message(speed.to_string()),
+                    (None, Some(eta)) => self.pb.set_message(format!("ETA {}", eta)),
+                    (None, None) => {}
+                }
+                self.emit(ProgressEvent {
+                    stage: "download",
+                    percent: Some(percent),
+                    speed,
+                    eta,
+                    ..Default::default()
+                });
+            }
+            return true;
+        }
+        if line.starts_with("[Merger]") {
+            self.emit(ProgressEvent {
+                stage: "merge",
+                ..Default::default()
+            });
+        } else if POSTPROCESS_PREFIXES.iter().any(|p| line.starts_with(p)) {
+            self.emit(ProgressEvent {
+                stage: "postprocess",
+                ..Default::default()
+            });
+        }
+        false
+    }
+
+    /// Shows a line of yt-dlp output without breaking the progress display. In JSON mode
+    /// everything goes to stderr so stdout only carries events.
+    fn show(&self, line: &str, stderr: bool) {
+        if stderr || self.json_url.is_some() {
+            self.pb.suspend(|| eprintln!("{}", line));
+        } else {
+            self.pb.suspend(|| println!("{}", line));
+        }
+    }
+}
+
+/// Returns the item number and item count of a playlist's "Downloading item N of M" line.
+fn playlist_item(line: &str) -> Option<(u32, u32)> {
+    static ITEM_REGEX: OnceLock<Regex> = OnceLock::new();
+    let item_regex = ITEM_REGEX.get_or_init(|| {
+        Regex::new(r"\[download\] Downloading (?:item|video) (\d+) of (\d+)").unwrap()
+    });
+    let caps = item_regex.captures(line)?;
+    Some((caps[1].parse().ok()?, caps[2].parse().ok()?))
+}
+
+/// Expands the `--user-agent` value, mapping the "chrome" and "firefox" shortcuts to the
+/// built-in browser strings.
+pub fn resolve_user_agent(user_agent: Option<&str>) -> String {
+    match user_agent {
+        None => DEFAULT_USER_AGENT.to_string(),
+        Some(ua) if ua.eq_ignore_ascii_case("chrome") => CHROME_USER_AGENT.to_string(),
+        Some(ua) if ua.eq_ignore_ascii_case("firefox") => FIREFOX_USER_AGENT.to_string(),
+        Some(ua) => ua.to_string(),
+    }
+}
+
+/// Returns whether a line of yt-dlp error output describes a permanent failure.
+fn is_fatal_error(line: &str) -> bool {
+    let line = line.to_lowercase();
+    FATAL_ERROR_PATTERNS
+        .iter()
+        .chain(UPCOMING_STREAM_PATTERNS)
+        .any(|pattern| line.contains(pattern))
+}
+
+/// Returns whether a line of yt-dlp error output is about a stream that has not started.
+fn is_upcoming_stream_error(line: &str) -> bool {
+    let line = line.to_lowercase();
+    UPCOMING_STREAM_PATTERNS
+        .iter()
+        .any(|pattern| line.contains(pattern))
+}
+
+/// Returns whether yt-dlp can embed a thumbnail into files with the given extension.
+fn supports_embedded_thumbnail(ext: &str) -> bool {
+    matches!(
+        ext,
+        "mp3" | "mkv" | "mka" | "ogg" | "opus" | "flac" | "m4a" | "mp4" | "m4v" | "mov"
+    )
+}
+
+/// Formats `cmd` as a single line that can be pasted into a shell, quoting arguments that
+/// contain spaces or shell metacharacters. On Unix they are single-quoted, so `$`, backticks
+/// and backslashes reach yt-dlp unchanged.
+pub fn format_command(cmd: &Command) -> String {
+    let quote = |arg: &str| {
+        let safe = !arg.is_empty()
+            && arg
+                .chars()
+                .all(|c| c.is_ascii_alphanumeric() || "-_./:=,+@%".contains(c));
+        if safe {
+            arg.to_string()
+        } else if cfg!(windows) {
+            format!("\"{}\"", arg.replace('"', "\\\""))
+        } else {
+            format!("'{}'", arg.replace('\'', r"'\''"))
+        }
+    };
+    std::iter::once(cmd.get_program())
+        .chain(cmd.get_args())
+        .map(|arg| quote(&arg.to_string_lossy()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+/// Records the files yt-dlp reports writing in `files`, keeping only final outputs: when
+/// formats are merged or audio is extracted, the intermediate files sharing the result's
+/// name are replaced by the result, and files moved out of the temp directory are recorded
+/// under their new path. Descriptions and info JSON files are announced with "[info]
+/// Writing" lines and aren't recorded. New files are tagged with `video_id`, the video
+/// yt-dlp is currently downloading.
+fn track_output_file(line: &str, video_id: Option<&str>, files: &Mutex<Vec<DownloadOutcome>>) {
+    static DESTINATION_REGEX: OnceLock<Regex> = OnceLock::new();
+    static FINAL_REGEX: OnceLock<Regex> = OnceLock::new();
+    static MOVE_REGEX: OnceLock<Regex> = OnceLock::new();
+    let destination_regex = DESTINATION_REGEX.get_or_init(|| {
+        Regex::new(r"^\[download\] (?:Destination: (.+)|(.+) has already been downloaded)")
+            .unwrap()
+    });
+    let move_regex = MOVE_REGEX.get_or_init(|| {
+        Regex::new(r#"^\[MoveFiles\] Moving file "(.+)" to "(.+)"$"#).unwrap()
+    });
+    let final_regex = FINAL_REGEX.get_or_init(|| {
+        Regex::new(concat!(
+            r#"^(?:\[Merger\] Merging formats into "(.+)""#,
+            r"|\[(?:ExtractAudio|VideoConvertor|VideoRemuxer)\] Destination: (.+))"
+        ))
+        .unwrap()
+    });
+
+    let mut files = files.lock().unwrap();
+    if let Some(caps) = destination_regex.captures(line) {
+        let path = PathBuf::from(caps.get(1).or_else(|| caps.get(2)).unwrap().as_str());
+        if !files.iter().any(|file| file.path == path) {
+            let mut outcome = DownloadOutcome::new(path);
+            outcome.skipped = caps.get(2).is_some();
+            outcome.video_id = video_id.map(str::to_string);
+            files.push(outcome);
+        }
+    } else if let Some(caps) = final_regex.captures(line) {
+        let path = PathBuf::from(caps.get(1).or_else(|| caps.get(2)).unwrap().as_str());
+        if let Some(stem) = path.file_stem().map(|stem| stem.to_string_lossy().into_owned()) {
+            let prefix = format!("{}.", stem);
+            files.retain(|file| {
+                let name = file.path.file_name().map(|name| name.to_string_lossy());
+                !name.is_some_and(|name| name.starts_with(&prefix))
+            });
+        }
+        let mut outcome = DownloadOutcome::new(path);
+        outcome.merged = caps.get(1).is_some();
+        outcome.video_id = video_id.map(str::to_string);
+        files.push(outcome);
+    } else if let Some(caps) = move_regex.captures(line) {
+        // Finished files are moved out of the temp directory into the output directory.
+        let from = PathBuf::from(&caps[1]);
+        let to = PathBuf::from(&caps[2]);
+        match files.iter_mut().find(|file| file.path == from) {
+            Some(file) => file.path = to,
+            None => files.push(DownloadOutcome {
+                video_id: video_id.map(str::to_string),
+                ..DownloadOutcome::new(to)
+            }),
+        }
+    }
+}
+
+/// Downloads `url` with yt-dlp, resuming partial files (`-c`) unless `no_resume` is set.
+/// Separate video and audio streams are merged into `container` (mp4, mkv or webm); for mp4
+/// and webm the format selector prefers streams that already fit, so ffmpeg only remuxes
+/// them. When `audio_only` is set, the audio track is extracted to `audio_format` instead.
+/// The progress bar is added to `progress` so parallel downloads render stacked.
+/// Returns the files yt-dlp produced.
+pub fn download_video(
+    options: &DownloadOptions,
+    url: &str,
+    progress: &MultiProgress,
+) -> Result<Vec<DownloadOutcome>> {
+    let output = &options.output;
+    let home_path = format!("home:{}", output.display());
+    let temp_path = format!("temp:{}", output.join(TEMP_SUBDIR).display());
+    let format = format_spec(options);
+
+    if options.skip_existing && !options.dry_run {
+        match existing_files(options, url) {
+            Ok(Some(paths)) => {
+                return Ok(paths
+                    .into_iter()
+                    .map(|path| {
+                        info!("Already downloaded: {}", path.display());
+                        DownloadOutcome {
+                            bytes: std::fs::metadata(&path).ok().map(|m| m.len()),
+                            skipped: true,
+                            ..DownloadOutcome::new(path)
+                        }
+                    })
+                    .collect());
+            }
+            Ok(None) => {}
+            Err(e) => warn!("{:#}; downloading anyway.", e),
+        }
+    }
+    if options.no_resume && !options.dry_run {
+        if let Err(e) = remove_stale_partial_files(options, url) {
+            warn!("{:#}", e);
+        }
+    }
+    info!("Downloading video from: {}", url);
+
+    let mut cmd = Command::new(&options.yt_dlp_path);
+    if options.audio_only {
+        cmd.args([
+            "-x", // extract audio
+            "--audio-format",
+            &options.audio_format,
+            "--audio-quality",
+            "0", // best VBR quality
+        ]);
+    } else {
+        cmd.args([
+            "-f",
+            &format,
+            "--merge-output-format",
+            &options.container,
+        ]);
+    }
+    if let Some(sort) = format_sort(options) {
+        cmd.args(["-S", &sort]);
+    }
+    // Resume partial downloads unless --no-resume asks for a clean restart.
+    cmd.arg(if options.no_resume { "--no-continue" } else { "-c" });
+    cmd.args([
+        "-P",
+        &home_path,
+        "-P",
+        &temp_path,
+        "-o",
+        &options.output_template,
+        "--ffmpeg-location",
+        options.ffmpeg_path.to_str().unwrap(),
+        "--newline",
+    ]);
+    if let Some(user_agent) = &options.user_agent {
+        cmd.args(["--user-agent", user_agent]);
+    }
+    if options.no_playlist {
+        cmd.arg("--no-playlist");
+    } else if is_playlist_url(url) {
+        info!("{} is a playlist; the progress bar restarts for each item.", url);
+    }
+    if let Some(items) = &options.playlist_items {
+        cmd.args(["--playlist-items", items]);
+    }
+    for section in &options.sections {
+        cmd.args(["--download-sections", section]);
+    }
+    if let Some(archive) = &options.archive {
+        cmd.arg("--download-archive").arg(archive);
+    }
+    if options.no_mtime {
+        cmd.arg("--no-mtime");
+    }
+    if options.restrict_filenames {
+        cmd.arg("--restrict-filenames");
+    }
+    if options.windows_filenames {
+        cmd.arg("--windows-filenames");
+    }
+    if let Some(rate) = &options.limit_rate {
+        cmd.args(["--limit-rate", rate]);
+    }
+    if options.live_from_start {
+        cmd.arg("--live-from-start");
+    }
+    if let Some(secs) = options.wait_for_video {
+        cmd.arg("--wait-for-video").arg(secs.to_string());
+    }
+    if options.concurrent_fragments > 1 {
+        cmd.arg("--concurrent-fragments")
+            .arg(options.concurrent_fragments.to_string());
+    }
+    if let Some(proxy) = &options.proxy {
+        cmd.args(["--proxy", proxy]);
+    }
+    if options.embed_metadata {
+        // `--add-metadata` is an alias of `--embed-metadata`, so one flag covers both.
+        cmd.arg("--embed-metadata");
+        let container = if options.audio_only {
+            options.audio_format.as_str()
+        } else {
+            options.container.as_str()
+        };
+        if supports_embedded_thumbnail(container) {
+            cmd.arg("--embed-thumbnail");
+        } else {
+            warn!("Thumbnails cannot be embedded into {} files, skipping.", container);
+        }
+    }
+    if options.write_description {
+        cmd.arg("--write-description");
+    }
+    if options.write_info_json {
+        cmd.arg("--write-info-json");
+    }
+    if let Some(categories) = &options.sponsorblock_remove {
+        cmd.args(["--sponsorblock-remove", categories]);
+    }
+    if let Some(categories) = &options.sponsorblock_mark {
+        cmd.args(["--sponsorblock-mark", categories]);
+    }
+    if options.subs || options.auto_subs || options.embed_subs {
+        if options.subs {
+            cmd.arg("--write-subs");
+        }
+        if options.subs || options.auto_subs {
+            cmd.arg("--write-auto-subs");
+        }
+        cmd.args(["--sub-langs", &options.sub_langs, "--convert-subs", "srt"]);
+        if options.embed_subs {
+            cmd.arg("--embed-subs");
+        }
+    }
+    if let Some(cookies) = &options.cookies {
+        cmd.arg("--cookies").arg(cookies);
+    }
+    if let Some(browser) = &options.cookies_from_browser {
+        cmd.args(["--cookies-from-browser", browser]);
+    }
+    if !options.no_default_headers {
+        for (key, value) in DEFAULT_HEADERS {
+            cmd.args(["--add-header", &format!("{}: {}", key, value)]);
+        }
+    }
+    if options.verbose {
+        cmd.arg("--verbose");
+    }
+    cmd.args(&options.yt_dlp_args);
+    cmd.arg(url);
+
+    if options.dry_run {
+        println!("{}", format_command(&cmd));
+        return Ok(Vec::new());
+    }
+
+    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
+    info!("Running command: {:?}", cmd);
+
+    // Put yt-dlp in its own process group so a terminal Ctrl-C reaches it only once,
+    // through `signal_children`. The group also holds the ffmpeg processes yt-dlp starts, so
+    // signalling it stops those too. The terminal no longer sends the group SIGHUP, so the
+    // Ctrl-C handler also runs on SIGHUP and SIGTERM and stops the group itself.
+    #[cfg(unix)]
+    std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+
+    let mut child = cmd.spawn().with_context(|| "Failed to spawn yt-dlp process")?;
+    let child_id = child.id();
+    RUNNING_CHILDREN.lock().unwrap().push(child_id);
+
+    let reporter = ProgressReporter::new(options, url, progress);
+    let finished_reporter = reporter.clone();
+
+    let files = Arc::new(Mutex::new(Vec::new()));
+
+    let last_output = Arc::new(Mutex::new(Instant::now()));
+
+    // Both pipes are read to the end even when the lines are hidden, so yt-dlp never
+    // blocks on a full pipe.
+    let quiet = options.quiet;
+    let stdout_reporter = reporter.clone();
+    let stdout_files = Arc::clone(&files);
+    let stdout_last_output = Arc::clone(&last_output);
+    let waiting = Arc::new(AtomicBool::new(false));
+    let stdout_waiting = Arc::clone(&waiting);
+    let video_id = Arc::new(Mutex::new(None::<String>));
+    let stdout_video_id = Arc::clone(&video_id);
+    let low_space = Arc::new(Mutex::new(None));
+    let stdout_low_space = Arc::clone(&low_space);
+    let space_output = output.clone();
+    let required_space = options.require_free_space;
+    let stdout = child.stdout.take().expect("Failed to capture stdout");
+    let stdout_thread = thread::spawn(move || {
+        let reader = BufReader::new(stdout);
+        let mut previous = String::new();
+        for line in reader.lines().map_while(Result::ok) {
+            note_activity(&line, &mut previous, &stdout_last_output);
+            stdout_waiting.store(line.starts_with("[wait]"), Ordering::SeqCst);
+            // The first item was covered by the check before the download started.
+            if playlist_item(&line).is_some_and(|(item, _)| item > 1) {
+                if let Err(e) = check_free_space(&space_output, required_space) {
+                    *stdout_low_space.lock().unwrap() = Some(format!("{:#}", e));
+                    signal_process(child_id, true);
+                }
+            }
+            if let Some(id) = downloaded_video_id(&line) {
+                *stdout_video_id.lock().unwrap() = Some(id.to_string());
+            }
+            let id = stdout_video_id.lock().unwrap().clone();
+            track_output_file(&line, id.as_deref(), &stdout_files);
+            if !stdout_reporter.update(&line) && !quiet {
+                stdout_reporter.show(&line, false);
+            }
+        }
+    });
+
+    let stderr_reporter = reporter;
+    let stderr_files = Arc::clone(&files);
+    let stderr_video_id = Arc::clone(&video_id);
+    let stderr_last_output = Arc::clone(&last_output);
+    let stderr = child.stderr.take().expect("Failed to capture stderr");
+    let stderr_thread = thread::spawn(move || {
+        let reader = BufReader::new(stderr);
+        let mut errors = Vec::new();
+        let mut previous = String::new();
+        for line in reader.lines().map_while(Result::ok) {
+            note_activity(&line, &mut previous, &stderr_last_output);
+            let id = stderr_video_id.lock().unwrap().clone();
+            track_output_file(&line, id.as_deref(), &stderr_files);
+            if !stderr_reporter.update(&line) {
+                let is_error = line.starts_with("ERROR:");
+                if !quiet || is_error || line.starts_with("WARNING:") {
+                    stderr_reporter.show(&line, true);
+                }
+                if is_error {
+                    errors.push(line);
+                }
+            }
+        }
+        errors
+    });
+
+    let timeout = options.timeout.map(Duration::from_secs);
+    let status = wait_for_child(&mut child, &last_output, &waiting, timeout);
+    RUNNING_CHILDREN.lock().unwrap().retain(|&id| id != child_id);
+    let status = status.with_context(|| "Failed to wait on yt-dlp process")?;
+    finished_reporter.finish();
+
+    stdout_thread.join().expect("Stdout thread panicked");
+    let errors = stderr_thread.join().expect("Stderr thread panicked");
+
+    if let Some(reason) = low_space.lock().unwrap().take() {
+        error!("Stopped before the next playlist item: {}", reason);
+        return Err(DownloadError::LowDiskSpace(reason).into());
+    }
+
+    let status = match status {
+        Some(status) => status,
+        None => {
+            let secs = options.timeout.unwrap_or_default();
+            error!("yt-dlp made no progress for {} seconds, stopped it.", secs);
+            return Err(DownloadError::Stalled(secs).into());
+        }
+    };
+
+    if !status.success() {
+        error!("yt-dlp failed with status: {}", status);
+        let error = match errors.into_iter().find(|line| is_fatal_error(line)) {
+            Some(line) => {
+                if is_upcoming_stream_error(&line) {
+                    warn!("The stream has not started yet; use --wait-for-video to wait for it.");
+                }
+                DownloadError::Fatal(line)
+            }
+            None => DownloadError::Failed(status),
+        };
+        return Err(error.into());
+    }
+
+    let mut files = std::mem::take(&mut *files.lock().unwrap());
+    if files.is_empty() {
+        info!("Download complete! Saved to {}", output.display());
+    }
+    for file in &mut files {
+        file.bytes = std::fs::metadata(&file.path).ok().map(|m| m.len());
+        info!("Download complete! Saved to {}", file.path.display());
+    }
+    if options.mtime_now {
+        let now = FileTime::now();
+        for file in &files {
+            if let Err(e) = filetime::set_file_mtime(&file.path, now) {
+                warn!("Failed to update the modification time of {}: {}", file.path.display(), e);
+            }
+        }
+    }
+    if options.verify {
+        let ffprobe = ffprobe_path(&options.ffmpeg_path);
+        for file in files.iter().filter(|file| !file.skipped && is_media_file(&file.path)) {
+            if let Err(e) = verify_media_file(&ffprobe, &file.path) {
+                error!("{:#}", e);
+                // Remove the file, and its video from the archive yt-dlp already added it to,
+                // so the retry downloads it again instead of skipping it. The other videos of
+                // a playlist verified fine and stay archived.
+                let _ = std::fs::remove_file(&file.path);
+                if let (Some(archive), Some(id)) = (&options.archive, &file.video_id) {
+                    if let Err(e) = remove_from_archive(archive, id) {
+                        warn!("{:#}", e);
+                    }
+                }
+                return Err(DownloadError::Unplayable(format!("{:#}", e)).into());
+            }
+        }
+    }
+    info!("The downloaded video is now detached from the downloader.");
+    Ok(files)
+}
+
+/// Returns the yt-dlp `-f` value for a video download.
+fn format_spec(options: &DownloadOptions) -> String {
+    match &options.format {
+        // The chosen format may be video only, so add the best audio when it can be merged.
+        Some(id) => format!("{id}+bestaudio/{id}"),
+        // The sort order picks the streams, so select from all of them.
+        None if options.format_sort.is_some() => match options.quality.as_str() {
+            "worst" => "worstvideo*+worstaudio/worst".to_string(),
+            _ => "bestvideo*+bestaudio/best".to_string(),
+        },
+        None => format_selector(&options.quality, &options.container),
+    }
+}
+
+/// Returns the field name of a `--format-sort` field such as `+res:1080` or `vcodec~vp9`.
+pub fn format_sort_field_name(field: &str) -> &str {
+    let name = field.trim_start_matches('+');
+    name.split([':', '~']).next().unwrap_or("")
+}
+
+/// Returns the `-S` value for `--format-sort`, led by `res:HEIGHT` for a `--quality`
+/// height unless the user sorts by resolution already. Unlike the `[height<=N]` filter
+/// this is a preference: a larger format is picked when no smaller one exists.
+fn format_sort(options: &DownloadOptions) -> Option<String> {
+    let sort = options.format_sort.as_deref()?;
+    let sorts_by_height = sort
+        .split(',')
+        .any(|field| matches!(format_sort_field_name(field), "res" | "height"));
+    if options.format.is_some() || sorts_by_height || options.quality.parse::<u32>().is_err() {
+        Some(sort.to_string())
+    } else {
+        Some(format!("res:{},{}", options.quality, sort))
+    }
+}
+
+/// Resolves the paths under `home` that yt-dlp would save `url` to, with
+/// `yt-dlp --get-filename`. With `audio_only` these name the file before conversion.
+fn resolve_filenames(options: &DownloadOptions, url: &str, home: &Path) -> Result<Vec<PathBuf>> {
+    let home_path = format!("home:{}", home.display());
+    let format = format_spec(options);
+    let sort = format_sort(options);
+    let mut flags = vec!["--get-filename", "-P", &home_path, "-o", &options.output_template];
+    if let Some(sort) = &sort {
+        flags.extend(["-S", sort]);
+    }
+    if options.audio_only {
+        flags.push("-x");
+    } else {
+        flags.extend(["-f", &format, "--merge-output-format", &options.container]);
+    }
+    if options.restrict_filenames {
+        flags.push("--restrict-filenames");
+    }
+    if options.windows_filenames {
+        flags.push("--windows-filenames");
+    }
+    if let Some(items) = &options.playlist_items {
+        flags.extend(["--playlist-items", items]);
+    }
+    let output = query_command(options, url, &flags)
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("Failed to execute {:?}", options.yt_dlp_path))?;
+    if !output.status.success() {
+        anyhow::bail!("yt-dlp could not resolve the file name for {}", url);
+    }
+    Ok(String::from_utf8_lossy(&output.stdout)
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .map(PathBuf::from)
+        .collect())
+}
+
+/// Returns the files `url` would be saved as if every one already exists and is non-empty,
+/// or `None` if anything still needs downloading.
+fn existing_files(options: &DownloadOptions, url: &str) -> Result<Option<Vec<PathBuf>>> {
+    let mut files = Vec::new();
+    for mut path in resolve_filenames(options, url, &options.output)? {
+        // yt-dlp names the source file; the audio is converted afterwards.
+        if options.audio_only {
+            path.set_extension(&options.audio_format);
+        }
+        if !std::fs::metadata(&path).is_ok_and(|m| m.len() > 0) {
+            return Ok(None);
+        }
+        files.push(path);
+    }
+    Ok((!files.is_empty()).then_some(files))
+}
+
+/// Deletes the `.part` and `.ytdl` files that earlier attempts to download `url` left in
+/// the temp directory. Partial files of other URLs, e.g. from parallel downloads, are kept.
+fn remove_stale_partial_files(options: &DownloadOptions, url: &str) -> Result<()> {
+    let temp = options.output.join(TEMP_SUBDIR);
+    for target in resolve_filenames(options, url, &temp)? {
+        let stem = match target.file_stem().and_then(|stem| stem.to_str()) {
+            Some(stem) => stem.to_string(),
+            None => continue,
+        };
+        let entries = match target.parent().map(std::fs::read_dir) {
+            Some(Ok(entries)) => entries,
+            _ => continue,
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if is_partial_file_of(&name, &stem) {
+                info!("Removing stale partial file {}", entry.path().display());
+                std::fs::remove_file(entry.path()).with_context(|| {
+                    format!("Failed to remove partial file {}", entry.path().display())
+                })?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Returns whether `name` is a partial file of the download saved as `<stem>.<ext>`.
+/// Separate video and audio streams get names like "<stem>.f137.mp4.part", and fragmented
+/// downloads "<stem>.mp4.part-Frag12". The stem must be followed by a dot so that "Intro"
+/// doesn't match the partial files of "Intro 2".
+fn is_partial_file_of(name: &str, stem: &str) -> bool {
+    let partial =
+        name.ends_with(".part") || name.ends_with(".ytdl") || name.contains(".part-Frag");
+    partial && name.strip_prefix(stem).is_some_and(|rest| rest.starts_with('.'))
+}
+
+/// Returns the id of the video a yt-dlp "[info] ID: Downloading N format(s)" line announces.
+fn downloaded_video_id(line: &str) -> Option<&str> {
+    static INFO_REGEX: OnceLock<Regex> = OnceLock::new();
+    let info_regex = INFO_REGEX
+        .get_or_init(|| Regex::new(r"^\[info\] ([^\s:]+): Downloading \d+ format\(s\)").unwrap());
+    Some(info_regex.captures(line)?.get(1)?.as_str())
+}
+
+/// Removes the entry of the video `id` from the `--archive` file, whose lines have the form
+/// "<extractor> <id>".
+fn remove_from_archive(archive: &Path, id: &str) -> Result<()> {
+    let contents = std::fs::read_to_string(archive)
+        .with_context(|| format!("Failed to read archive file {}", archive.display()))?;
+    let kept: String = contents
+        .lines()
+        .filter(|line| line.split_whitespace().nth(1) != Some(id))
+        .map(|line| format!("{}\n", line))
+        .collect();
+    std::fs::write(archive, kept)
+        .with_context(|| format!("Failed to update archive file {}", archive.display()))
+}
+
+/// Returns the ffprobe binary that ships next to the ffmpeg at `ffmpeg_path`.
+pub fn ffprobe_path(ffmpeg_path: &Path) -> PathBuf {
+    let name = if cfg!(windows) { "ffprobe.exe" } else { "ffprobe" };
+    ffmpeg_path.with_file_name(name)
+}
+
+/// Returns whether `path` is an audio or video file rather than a subtitle or thumbnail.
+fn is_media_file(path: &Path) -> bool {
+    let ext = path
+        .extension()
+        .map(|ext| ext.to_string_lossy().to_lowercase())
+        .unwrap_or_default();
+    !matches!(
+        ext.as_str(),
+        "srt" | "vtt" | "ass" | "lrc" | "jpg" | "jpeg" | "png" | "webp" | "json" | "description"
+    )
+}
+
+/// Checks with ffprobe that `path` has an audio or video stream and a positive duration.
+fn verify_media_file(ffprobe: &Path, path: &Path) -> Result<()> {
+    let output = Command::new(ffprobe)
+        .args(["-v", "error", "-show_entries", "format=duration:stream=codec_type"])
+        .args(["-of", "json"])
+        .arg(path)
+        .output()
+        .with_context(|| format!("Failed to execute {:?}", ffprobe))?;
+    if !output.status.success() {
+        anyhow::bail!(
+            "{} is not a readable media file: {}",
+            path.display(),
+            String::from_utf8_lossy(&output.stderr).trim()
+        );
+    }
+    let json: Value = serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("Failed to parse ffprobe output for {}", path.display()))?;
+    let has_stream = json["streams"].as_array().is_some_and(|streams| {
+        streams
+            .iter()
+            .any(|s| matches!(s["codec_type"].as_str(), Some("video" | "audio")))
+    });
+    if !has_stream {
+        anyhow::bail!("{} has no audio or video stream", path.display());
+    }
+    let duration = json["format"]["duration"]
+        .as_str()
+        .and_then(|d| d.parse::<f64>().ok())
+        .unwrap_or(0.0);
+    if duration <= 0.0 {
+        anyhow::bail!("{} has no playable duration", path.display());
+    }
+    info!("Verified {} ({:.0} seconds).", path.display(), duration);
+    Ok(())
+}
+
+/// Records in `last_output` that yt-dlp printed `line`, unless it only repeated the
+/// `previous` line: a progress line stuck at the same byte count is not progress.
+fn note_activity(line: &str, previous: &mut String, last_output: &Mutex<Instant>) {
+    if line != previous.as_str() {
+        *last_output.lock().unwrap() = Instant::now();
+        previous.clear();
+        previous.push_str(line);
+    }
+}
+
+/// Waits for `child` to exit. With a `timeout`, the child and the processes it started are
+/// killed once it has gone that long without printing a new line (as recorded in
+/// `last_output`), and `None` is returned.
+fn wait_for_child(
+    child: &mut Child,
+    last_output: &Mutex<Instant>,
+    waiting: &AtomicBool,
+    timeout: Option<Duration>,
+) -> io::Result<Option<ExitStatus>> {
+    let timeout = match timeout {
+        Some(timeout) => timeout,
+        None => return child.wait().map(Some),
+    };
+    loop {
+        if let Some(status) = child.try_wait()? {
+            return Ok(Some(status));
+        }
+        // yt-dlp counts down on a single line while it waits for a stream, so those waits
+        // look silent and must not count as a stall.
+        if !waiting.load(Ordering::SeqCst) && last_output.lock().unwrap().elapsed() >= timeout {
+            // Killing only yt-dlp would leave the ffmpeg it started for HLS or sections
+            // running, holding the output pipes open.
+            signal_process(child.id(), true);
+            child.wait()?;
+            return Ok(None);
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+}
+
+/// Builds a yt-dlp command that queries `url` with `flags` (e.g. `-F`) instead of
+/// downloading it, using the same binary, proxy and cookies as a download would.
+fn query_command(options: &DownloadOptions, url: &str, flags: &[&str]) -> Command {
+    let mut cmd = Command::new(&options.yt_dlp_path);
+    cmd.args(flags);
+    if let Some(user_agent) = &options.user_agent {
+        cmd.args(["--user-agent", user_agent]);
+    }
+    cmd.arg("--ffmpeg-location").arg(&options.ffmpeg_path);
+    if options.no_playlist {
+        cmd.arg("--no-playlist");
+    }
+    if let Some(proxy) = &options.proxy {
+        cmd.args(["--proxy", proxy]);
+    }
+    if let Some(cookies) = &options.cookies {
+        cmd.arg("--cookies").arg(cookies);
+    }
+    if let Some(browser) = &options.cookies_from_browser {
+        cmd.args(["--cookies-from-browser", browser]);
+    }
+    cmd.args(&options.yt_dlp_args);
+    cmd.arg(url);
+    cmd
+}
+
+/// Prints the formats yt-dlp can download for `url`.
+pub fn list_formats(options: &DownloadOptions, url: &str) -> Result<()> {
+    let mut cmd = query_command(options, url, &["-F"]);
+    if options.dry_run {
+        println!("{}", format_command(&cmd));
+        return Ok(());
+    }
+    let status = cmd
+        .status()
+        .with_context(|| format!("Failed to execute {:?} -F", options.yt_dlp_path))?;
+    if !status.success() {
+        anyhow::bail!("yt-dlp -F failed for {} with status {}", url, status);
+    }
+    Ok(())
+}
+
+/// Returns the formats yt-dlp can download for `url` as (format id, table row) pairs, in
+/// the order `yt-dlp -F` lists them.
+pub fn available_formats(options: &DownloadOptions, url: &str) -> Result<Vec<(String, String)>> {
+    let output = query_command(options, url, &["-F"])
+        .stderr(Stdio::null())
+        .output()
+        .with_context(|| format!("Failed to execute {:?} -F", options.yt_dlp_path))?;
+    if !output.status.success() {
+        anyhow::bail!("yt-dlp -F failed for {} with status {}", url, output.status);
+    }
+    // The table starts after a line of dashes under the column headers.
+    let is_separator = |line: &str| {
+        let line = line.trim();
+        !line.is_empty() && line.chars().all(|c| c == '-' || c == '─')
+    };
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout
+        .lines()
+        .skip_while(|line| !is_separator(line))
+        .skip(1)
+        .filter_map(|line| {
+            let id = line.split_whitespace().next()?;
+            Some((id.to_string(), line.trim_end().to_string()))
+        })
+        .collect())
+}
+
+/// Returns the metadata yt-dlp reports for `url` without downloading it. For playlists this
+/// includes an `entries` array with each video's metadata.
+pub fn video_info(options: &DownloadOptions, url: &str) -> Result<Value> {
+    let output = query_command(options, url, &["--dump-single-json", "--no-download"])
+        .stderr(Stdio::inherit())
+        .output()
+        .with_context(|| format!("Failed to execute {:?}", options.yt_dlp_path))?;
+    if !output.status.success() {
+        anyhow::bail!("yt-dlp failed to fetch info for {} with status {}", url, output.status);
+    }
+    serde_json::from_slice(&output.stdout)
+        .with_context(|| format!("Failed to parse the info JSON for {}", url))
+}
+
+/// Returns whether a stop has been requested with `request_stop`.
+pub fn stop_requested() -> bool {
+    STOP_REQUESTED.load(Ordering::SeqCst)
+}
+
+/// Asks the running yt-dlp processes to stop so they can clean up, or kills them outright
+/// with `force`. On Windows the console already delivers Ctrl-C to every attached process,
+/// so only `force` sends anything.
+fn signal_children(force: bool) {
+    for &id in RUNNING_CHILDREN.lock().unwrap().iter() {
+        signal_process(id, force);
+    }
+}
+
+/// Asks the yt-dlp process `id` and the processes it started to stop, or kills them with
+/// `force`. On Unix the signal goes to the process group `download_video` put yt-dlp in.
+/// Does nothing on Windows without `force`; see `signal_children`.
+fn signal_process(id: u32, force: bool) {
+    let result = if cfg!(windows) {
+        if !force {
+            return;
+        }
+        Command::new("taskkill")
+            .args(["/F", "/T", "/PID", &id.to_string()])
+            .status()
+    } else {
+        let signal = if force { "-KILL" } else { "-INT" };
+        let group = format!("-{}", id);
+        Command::new("kill").args([signal, "--", &group]).status()
+    };
+    if let Err(e) = result {
+        warn!("Failed to stop yt-dlp process {}: {}", id, e);
+    }
+}
+
+/// Stops new downloads and retries from starting and interrupts the running yt-dlp
+/// processes. Meant to be called from a Ctrl-C handler.
+pub fn request_stop() {
+    STOP_REQUESTED.store(true, Ordering::SeqCst);
+    signal_children(false);
+}
+
+/// Kills the running yt-dlp processes, for when the user won't wait for them to stop.
+pub fn kill_running() {
+    signal_children(true);
+}
+
+/// Deletes the temp subdirectory of `output`, which holds the partial files of interrupted
+/// downloads.
+pub fn remove_partial_files(output: &Path) -> Result<()> {
+    let temp = output.join(TEMP_SUBDIR);
+    match std::fs::remove_dir_all(&temp) {
+        Err(e) if e.kind() != ErrorKind::NotFound => Err(e)
+            .with_context(|| format!("Failed to remove partial files in {}", temp.display())),
+        _ => Ok(()),
+    }
+}
+
+/// Runs the `--exec` hook for a downloaded file through the shell, with `{}` in `command`
+/// standing for the file path. The path is handed to the shell as an argument (`$1`, or a
+/// delayed-expansion variable with cmd) rather than spliced into the command, so a title
+/// containing `$(...)` or backticks can't run anything. The hook's output is logged.
+fn run_exec_hook(command: &str, file: &Path) -> Result<()> {
+    #[cfg(windows)]
+    let mut cmd = {
+        use std::os::windows::process::CommandExt;
+        let mut cmd = Command::new("cmd");
+        // `!VAR!` is expanded after the command line is parsed, so `&` or `|` in the path
+        // stay literal. cmd.exe doesn't understand the `\"` escapes `arg` would add around
+        // the quotes, so the command line is passed as written.
+        cmd.args(["/V:ON", "/C"]);
+        cmd.raw_arg(expand_exec_placeholder(command, "\"!YTDL_EXEC_FILE!\""));
+        cmd.env("YTDL_EXEC_FILE", file);
+        cmd
+    };
+    #[cfg(not(windows))]
+    let mut cmd = {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", &expand_exec_placeholder(command, "\"$1\""), "sh"]);
+        cmd.arg(file);
+        cmd
+    };
+    info!("Running post-download hook: {} (file: {})", command, file.display());
+
+    let output = cmd
+        .output()
+        .with_context(|| format!("Failed to run post-download hook: {}", command))?;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        info!("[exec] {}", line);
+    }
+    for line in String::from_utf8_lossy(&output.stderr).lines() {
+        warn!("[exec] {}", line);
+    }
+    if !output.status.success() {
+        anyhow::bail!("Post-download hook exited with {}", output.status);
+    }
+    info!("Post-download hook exited with {}", output.status);
+    Ok(())
+}
+
+/// Replaces `{}` in an `--exec` command with `quoted`, the already quoted reference to the
+/// file path. A placeholder the user quoted as `'{}'` or `"{}"` is replaced as a whole, since
+/// `'$1'` would leave the shell with a literal `$1`.
+fn expand_exec_placeholder(command: &str, quoted: &str) -> String {
+    command
+        .replace("'{}'", "{}")
+        .replace("\"{}\"", "{}")
+        .replace("{}", quoted)
+}
+
+/// Returns how long to wait before the given retry (starting at 1). With `backoff` the
+/// delay doubles on every retry and is jittered by ±20% so parallel workers don't retry
+/// in lockstep, never exceeding `max_backoff`.
+fn retry_delay_for(options: &DownloadOptions, retry: u32) -> Duration {
+    if !options.backoff {
+        return Duration::from_secs(options.retry_delay);
+    }
+    let exponent = retry.saturating_sub(1).min(32);
+    let delay = options.retry_delay.saturating_mul(1u64 << exponent);
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.subsec_nanos())
+        .unwrap_or(0);
+    let jitter = 0.8 + 0.4 * (f64::from(nanos) / 1_000_000_000.0);
+    Duration::from_secs_f64((delay as f64 * jitter).min(options.max_backoff as f64))
+}
+
+/// Returns the URL being downloaded by `download_video_robust` on the current thread.
+pub fn current_url() -> Option<String> {
+    CURRENT_URL.with(|current| current.borrow().clone())
+}
+
+/// Sets the current thread's URL for `current_url` until dropped.
+struct UrlContext;
+
+impl UrlContext {
+    fn enter(url: &str) -> Self {
+        CURRENT_URL.with(|current| *current.borrow_mut() = Some(url.to_string()));
+        UrlContext
+    }
+}
+
+impl Drop for UrlContext {
+    fn drop(&mut self) {
+        CURRENT_URL.with(|current| *current.borrow_mut() = None);
+    }
+}
+
+/// Makes a single download attempt. `YtDlp` is the real implementation; tests substitute
+/// fakes to exercise the retry logic without spawning processes.
+pub trait Downloader {
+    fn run(
+        &self,
+        options: &DownloadOptions,
+        url: &str,
+        progress: &MultiProgress,
+    ) -> Result<Vec<DownloadOutcome>>;
+}
+
+/// Downloads with the yt-dlp binary through `download_video`.
+pub struct YtDlp;
+
+impl Downloader for YtDlp {
+    fn run(
+        &self,
+        options: &DownloadOptions,
+        url: &str,
+        progress: &MultiProgress,
+    ) -> Result<Vec<DownloadOutcome>> {
+        download_video(options, url, progress)
+    }
+}
+
+/// Formats a byte count for display, e.g. "12.3 MiB".
+pub fn format_size(bytes: u64) -> String {
+    const UNITS: &[&str] = &["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = 0;
+    while size >= 1024.0 && unit < UNITS.len() - 1 {
+        size /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", size, UNITS[unit])
+    }
+}
+
+/// Checks the free space on the volume holding `output`. Fails when it is below `required`
+/// and logs a warning when it is below 1 GiB. If the space cannot be determined, a warning
+/// is logged and the check passes.
+pub fn check_free_space(output: &Path, required: Option<u64>) -> Result<()> {
+    let available = match fs2::available_space(output) {
+        Ok(available) => available,
+        Err(e) => {
+            warn!("Could not check free space in {}: {}", output.display(), e);
+            return Ok(());
+        }
+    };
+    if let Some(required) = required.filter(|&required| available < required) {
+        anyhow::bail!(
+            "only {} free in {}, but {} is required",
+            format_size(available),
+            output.display(),
+            format_size(required)
+        );
+    }
+    if available < LOW_DISK_SPACE_WARNING {
+        warn!(
+            "Only {} free in {}; large downloads may fail.",
+            format_size(available),
+            output.display()
+        );
+    }
+    Ok(())
+}
+
+/// Retries `download_video` up to `max_retries` times (indefinitely if it is `None`),
+/// returning the last error once the retries are exhausted. Fatal errors such as a private
+/// video are returned immediately.
+pub fn download_video_robust(
+    options: &DownloadOptions,
+    url: &str,
+    progress: &MultiProgress,
+) -> Result<Vec<DownloadOutcome>> {
+    download_with_retries(&YtDlp, options, url, progress)
+}
+
+/// Like `download_video_robust`, but making each attempt with `downloader`.
+pub fn download_with_retries(
+    downloader: &impl Downloader,
+    options: &DownloadOptions,
+    url: &str,
+    progress: &MultiProgress,
+) -> Result<Vec<DownloadOutcome>> {
+    let _url_context = UrlContext::enter(url);
+    check_free_space(&options.output, options.require_free_space)?;
+    let mut retries = 0;
+    loop {
+        match downloader.run(options, url, progress) {
+            Ok(files) => {
+                if !options.dry_run {
+                    info!("Download completed successfully.");
+                }
+                if let Some(command) = &options.exec {
+                    // Files yt-dlp didn't download this time were already handled.
+                    for file in files.iter().filter(|file| !file.skipped) {
+                        match run_exec_hook(command, &file.path) {
+                            Err(e) if options.exec_strict => {
+                                error!("{:#}", e);
+                                return Err(DownloadError::HookFailed(format!("{:#}", e)).into());
+                            }
+                            Err(e) => warn!("{:#}", e),
+                            Ok(()) => {}
+                        }
+                    }
+                }
+                return Ok(files);
+            }
+            Err(e)
+                if e
+                    .downcast_ref::<DownloadError>()
+                    .is_some_and(|e| !e.is_retryable()) =>
+            {
+                error!("Not retrying {}: {}", url, e);
+                return Err(e);
+            }
+            Err(e) if stop_requested() => {
+                return Err(e.context("Download interrupted"));
+            }
+            Err(e) if options.max_retries.is_some_and(|max| retries >= max) => {
+                error!("Giving up on {} after {} retries: {:?}", url, retries, e);
+                return Err(e);
+            }
+            Err(e) => {
+                retries += 1;
+                let delay = retry_delay_for(options, retries);
+                let limit = options
+                    .max_retries
+                    .map_or_else(String::new, |max| format!(" of {}", max));
+                error!(
+                    "Download encountered an error: {:?}. Retry {}{} in {:.1} seconds...",
+                    e,
+                    retries,
+                    limit,
+                    delay.as_secs_f64()
+                );
+                thread::sleep(delay);
+                if stop_requested() {
+                    anyhow::bail!("Download interrupted before retry {}", retries);
+                }
+                info!("Resuming download...");
+            }
+        }
+    }
+}
+
+/// Downloads `urls` using up to `concurrency` worker threads that pull from a shared queue.
+/// `on_result` is called as each URL finishes. Returns one result per URL, in the order the
+/// URLs were given.
+pub fn download_batch(
+    options: &DownloadOptions,
+    urls: Vec<String>,
+    concurrency: usize,
+    on_result: &(dyn Fn(&BatchResult) + Sync),
+) -> Vec<BatchResult> {
+    let queue = Mutex::new(urls.into_iter().enumerate());
+    let results = Mutex::new(Vec::new());
+    let progress = MultiProgress::new();
+
+    thread::scope(|scope| {
+        for _ in 0..concurrency.max(1) {
+            scope.spawn(|| loop {
+                if stop_requested() {
+                    break;
+                }
+                let next = queue.lock().unwrap().next();
+                let (index, url) = match next {
+                    Some(next) => next,
+                    None => break,
+                };
+                let started = Instant::now();
+                let outcome = check_url(&url, options.allow_any_host)
+                    .and_then(|()| download_video_robust(options, &url, &progress));
+                let elapsed_secs = started.elapsed().as_secs_f64();
+                let result = match outcome {
+                    Ok(files) => BatchResult {
+                        url,
+                        success: true,
+                        files,
+                        error: None,
+                        elapsed_secs,
+                    },
+                    Err(e) => {
+                        error!("Failed to download {}: {}", url, e);
+                        BatchResult {
+                            url,
+                            success: false,
+                            files: Vec::new(),
+                            error: Some(format!("{:#}", e)),
+                            elapsed_secs,
+                        }
+                    }
+                };
+                if options.progress_json {
+                    ResultEvent::emit_all(&result);
+                }
+                on_result(&result);
+                results.lock().unwrap().push((index, result));
+            });
+        }
+    });
+
+    let mut results = results.into_inner().unwrap();
+    results.sort_by_key(|(index, _)| *index);
+    results.into_iter().map(|(_, result)| result).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+
+    /// Fails the first `failures` attempts, with a fatal error if `fatal` is set, then
+    /// succeeds.
+    struct FlakyDownloader {
+        failures: u32,
+        fatal: bool,
+        attempts: Cell<u32>,
+    }
+
+    impl FlakyDownloader {
+        fn new(failures: u32, fatal: bool) -> Self {
+            FlakyDownloader {
+                failures,
+                fatal,
+                attempts: Cell::new(0),
+            }
+        }
+    }
+
+    impl Downloader for FlakyDownloader {
+        fn run(
+            &self,
+            _options: &DownloadOptions,
+            _url: &str,
+            _progress: &MultiProgress,
+        ) -> Result<Vec<DownloadOutcome>> {
+            let attempt = self.attempts.get() + 1;
+            self.attempts.set(attempt);
+            if attempt > self.failures {
+                Ok(vec![DownloadOutcome::new(PathBuf::from("video.mp4"))])
+            } else if self.fatal {
+                Err(DownloadError::Fatal("ERROR: Private video".to_string()).into())
+            } else {
+                Err(anyhow::anyhow!("simulated network error"))
+            }
+        }
+    }
+
+    /// Succeeds with one downloaded file and one that was already there.
+    struct PartlySkippedDownloader;
+
+    impl Downloader for PartlySkippedDownloader {
+        fn run(
+            &self,
+            _options: &DownloadOptions,
+            _url: &str,
+            _progress: &MultiProgress,
+        ) -> Result<Vec<DownloadOutcome>> {
+            let skipped = DownloadOutcome {
+                skipped: true,
+                ..DownloadOutcome::new(PathBuf::from("old.mp4"))
+            };
+            Ok(vec![DownloadOutcome::new(PathBuf::from("new.mp4")), skipped])
+        }
+    }
+
+    fn test_options(max_retries: u32) -> DownloadOptions {
+        DownloadOptions {
+            yt_dlp_path: PathBuf::from("yt-dlp"),
+            ffmpeg_path: PathBuf::from("ffmpeg"),
+            output: PathBuf::from("downloads"),
+            output_template: "%(title)s.%(ext)s".to_string(),
+            archive: None,
+            restrict_filenames: false,
+            windows_filenames: false,
+            allow_any_host: false,
+            retry_delay: 0,
+            max_retries: Some(max_retries),
+            backoff: false,
+            max_backoff: 0,
+            timeout: None,
+            require_free_space: None,
+            limit_rate: None,
+            live_from_start: false,
+            wait_for_video: None,
+            concurrent_fragments: 1,
+            audio_only: false,
+            audio_format: "mp3".to_string(),
+            container: "mp4".to_string(),
+            quality: "720".to_string(),
+            format: None,
+            format_sort: None,
+            no_playlist: false,
+            playlist_items: None,
+            embed_metadata: false,
+            write_description: false,
+            write_info_json: false,
+            verify: false,
+            no_mtime: false,
+            mtime_now: false,
+            user_agent: Some(DEFAULT_USER_AGENT.to_string()),
+            no_default_headers: false,
+            proxy: None,
+            subs: false,
+            auto_subs: false,
+            embed_subs: false,
+            sub_langs: "en".to_string(),
+            cookies: None,
+            cookies_from_browser: None,
+            sponsorblock_remove: None,
+            sponsorblock_mark: None,
+            progress_json: false,
+            quiet: false,
+            verbose: false,
+            dry_run: false,
+            yt_dlp_args: Vec::new(),
+            sections: Vec::new(),
+            exec: None,
+            exec_strict: false,
+            skip_existing: false,
+            no_resume: false,
+        }
+    }
+
+    const URL: &str = "https://youtu.be/dQw4w9WgXcQ";
+
+    #[test]
+    fn retries_until_success() {
+        let downloader = FlakyDownloader::new(3, false);
+        let progress = MultiProgress::new();
+        let files = download_with_retries(&downloader, &test_options(5), URL, &progress).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(downloader.attempts.get(), 4);
+    }
+
+    #[test]
+    fn gives_up_after_max_retries() {
+        let downloader = FlakyDownloader::new(10, false);
+        let progress = MultiProgress::new();
+        let result = download_with_retries(&downloader, &test_options(2), URL, &progress);
+        assert!(result.is_err());
+        assert_eq!(downloader.attempts.get(), 3);
+    }
+
+    #[test]
+    fn retries_indefinitely_without_a_limit() {
+        let downloader = FlakyDownloader::new(8, false);
+        let mut options = test_options(0);
+        options.max_retries = None;
+        let progress = MultiProgress::new();
+        let files = download_with_retries(&downloader, &options, URL, &progress).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(downloader.attempts.get(), 9);
+    }
+
+    #[test]
+    fn does_not_retry_fatal_errors() {
+        let downloader = FlakyDownloader::new(1, true);
+        let progress = MultiProgress::new();
+        let result = download_with_retries(&downloader, &test_options(5), URL, &progress);
+        assert!(result.is_err());
+        assert_eq!(downloader.attempts.get(), 1);
+    }
+
+    #[test]
+    fn accepts_youtube_urls() {
+        for url in [
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ",
+            "http://youtube.com/watch?v=dQw4w9WgXcQ",
+            "https://m.youtube.com/watch?v=dQw4w9WgXcQ",
+            "https://youtu.be/dQw4w9WgXcQ",
+            "https://www.youtube.com/playlist?list=PL590L5WQmH8fJ54F369BLDSqIwcs-TCfs",
+        ] {
+            assert!(is_valid_url(url), "{}", url);
+            assert!(check_url(url, false).is_ok(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn rejects_non_http_schemes() {
+        for url in ["file:///etc/passwd", "mailto:foo@example.com", "ftp://host/video.mp4"] {
+            assert!(!is_valid_url(url), "{}", url);
+            assert!(check_url(url, true).is_err(), "{}", url);
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_urls() {
+        for url in ["", "not a url", "https://", "youtube.com/watch?v=dQw4w9WgXcQ"] {
+            assert!(!is_valid_url(url), "{}", url);
+        }
+    }
+
+    #[test]
+    fn other_sites_need_allow_any_host() {
+        let url = "https://vimeo.com/76979871";
+        assert!(is_valid_url(url));
+        assert!(check_url(url, false).is_err());
+        assert!(check_url(url, true).is_ok());
+    }
+
+    #[test]
+    fn normalizes_youtube_video_urls() {
+        let canonical = "https://www.youtube.com/watch?v=dQw4w9WgXcQ";
+        for url in [
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ&feature=share&t=42",
+            "https://m.youtube.com/watch?si=abc123&v=dQw4w9WgXcQ",
+            "https://youtu.be/dQw4w9WgXcQ?si=abc123",
+            "https://www.youtube.com/embed/dQw4w9WgXcQ?autoplay=1",
+            "https://www.youtube-nocookie.com/embed/dQw4w9WgXcQ",
+            "https://youtube.com/shorts/dQw4w9WgXcQ?feature=share",
+            "https://www.youtube.com/live/dQw4w9WgXcQ",
+        ] {
+            assert_eq!(normalize_url(url, false), canonical, "{}", url);
+        }
+    }
+
+    #[test]
+    fn drops_playlist_unless_kept() {
+        let url = "https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=PL123&index=2";
+        assert_eq!(normalize_url(url, false), "https://www.youtube.com/watch?v=dQw4w9WgXcQ");
+        assert_eq!(
+            normalize_url(url, true),
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=PL123"
+        );
+        let short = "https://youtu.be/dQw4w9WgXcQ?list=PL123";
+        assert_eq!(
+            normalize_url(short, true),
+            "https://www.youtube.com/watch?v=dQw4w9WgXcQ&list=PL123"
+        );
+    }
+
+    #[test]
+    fn leaves_other_urls_alone() {
+        for url in [
+            "https://www.youtube.com/playlist?list=PL123",
+            "https://www.youtube.com/@somechannel/videos",
+            "https://www.youtube.com/watch?v=tooshort",
+            "https://vimeo.com/76979871",
+            "not a url",
+        ] {
+            assert_eq!(normalize_url(url, false), url);
+        }
+    }
+
+    #[test]
+    fn leads_format_sort_with_quality_height() {
+        let mut options = test_options(5);
+        options.format_sort = Some("fps,vcodec:vp9".to_string());
+        assert_eq!(format_sort(&options).as_deref(), Some("res:720,fps,vcodec:vp9"));
+        assert_eq!(format_spec(&options), "bestvideo*+bestaudio/best");
+
+        options.format_sort = Some("+res:1080,fps".to_string());
+        assert_eq!(format_sort(&options).as_deref(), Some("+res:1080,fps"));
+
+        options.format_sort = Some("vcodec:vp9".to_string());
+        options.quality = "best".to_string();
+        assert_eq!(format_sort(&options).as_deref(), Some("vcodec:vp9"));
+
+        options.format_sort = None;
+        assert_eq!(format_sort(&options), None);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exec_hook_does_not_run_the_file_name() {
+        let dir = std::env::temp_dir().join(format!("ytdl-exec-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let marker = dir.join("ran");
+        let touch = format!("touch {}", marker.display());
+        let file = dir.join(format!("$({}) `{}`.mp4", touch, touch));
+        run_exec_hook("test -n {}", &file).unwrap();
+        let ran = marker.exists();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(!ran);
+    }
+
+    #[test]
+    fn expands_quoted_exec_placeholders() {
+        for command in ["mv {} /media", "mv '{}' /media", "mv \"{}\" /media"] {
+            assert_eq!(expand_exec_placeholder(command, "\"$1\""), "mv \"$1\" /media");
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exec_hook_accepts_a_quoted_placeholder() {
+        let dir = std::env::temp_dir().join(format!("ytdl-exec-quoted-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = dir.join("My video.mp4");
+        std::fs::write(&file, b"").unwrap();
+        let result = run_exec_hook("test -f '{}'", &file);
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert!(result.is_ok());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn formats_commands_for_posix_shells() {
+        let mut cmd = Command::new("yt-dlp");
+        cmd.args(["-o", "$HOME/%(title)s.%(ext)s", "it's", "`id` \\ \"x\""]);
+        let line = format_command(&cmd);
+        assert_eq!(line, r#"yt-dlp -o '$HOME/%(title)s.%(ext)s' 'it'\''s' '`id` \ "x"'"#);
+
+        // The shell must hand every argument back unchanged.
+        let echoed = Command::new("sh")
+            .args(["-c", &format!("printf '%s\\n' {}", line)])
+            .output()
+            .unwrap();
+        let printed = String::from_utf8(echoed.stdout).unwrap();
+        let expected: Vec<_> = std::iter::once(cmd.get_program())
+            .chain(cmd.get_args())
+            .map(|arg| arg.to_str().unwrap())
+            .collect();
+        assert_eq!(printed.lines().collect::<Vec<_>>(), expected);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn exec_hook_skips_files_that_were_not_downloaded() {
+        let log = std::env::temp_dir().join(format!("ytdl-exec-skipped-{}", std::process::id()));
+        let mut options = test_options(0);
+        options.exec = Some(format!("echo {{}} >> '{}'", log.display()));
+        let progress = MultiProgress::new();
+        let files = download_with_retries(&PartlySkippedDownloader, &options, URL, &progress);
+        let ran = std::fs::read_to_string(&log).unwrap();
+        std::fs::remove_file(&log).unwrap();
+        assert_eq!(files.unwrap().len(), 2);
+        assert_eq!(ran, "new.mp4\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn stall_timeout_kills_the_processes_yt_dlp_started() {
+        let mut cmd = Command::new("sh");
+        cmd.args(["-c", "sleep 30 & wait"]).stdout(Stdio::piped());
+        std::os::unix::process::CommandExt::process_group(&mut cmd, 0);
+        let mut child = cmd.spawn().unwrap();
+        let mut stdout = child.stdout.take().unwrap();
+        let started = Instant::now();
+        let last_output = Mutex::new(Instant::now());
+        let waiting = AtomicBool::new(false);
+        let timeout = Some(Duration::from_millis(500));
+
+        let status = wait_for_child(&mut child, &last_output, &waiting, timeout).unwrap();
+        // The pipe only closes once the sleep holding it has been killed too.
+        io::copy(&mut stdout, &mut io::sink()).unwrap();
+
+        assert!(status.is_none());
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn repeated_progress_lines_count_as_a_stall() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("ytdl-stall-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let script = dir.join("yt-dlp");
+        std::fs::write(
+            &script,
+            concat!(
+                "#!/bin/sh\nsleep 30 &\n",
+                "while :; do echo '[download]  42.0% of 10.00MiB'; sleep 0.2; done\n"
+            ),
+        )
+        .unwrap();
+        std::fs::set_permissions(&script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let mut options = test_options(0);
+        options.yt_dlp_path = script;
+        options.output = dir.clone();
+        options.timeout = Some(1);
+        options.quiet = true;
+
+        let started = Instant::now();
+        let result = download_video(&options, URL, &MultiProgress::new());
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let error = result.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(DownloadError::Stalled(1))), "{:#}", error);
+        assert!(started.elapsed() < Duration::from_secs(10));
+    }
+
+    #[test]
+    fn recognizes_fatal_errors() {
+        for line in [
+            "ERROR: [youtube] dQw4w9WgXcQ: Private video. Sign in if you've been granted access \
+             to this video",
+            "ERROR: [youtube] dQw4w9WgXcQ: Video unavailable. This video is no longer available \
+             because the YouTube account associated with this video has been terminated.",
+            "ERROR: [youtube] dQw4w9WgXcQ: Video unavailable. This video contains content from \
+             SME, who has blocked it on copyright grounds.",
+            "ERROR: [youtube] dQw4w9WgXcQ: This video has been removed by the uploader",
+        ] {
+            assert!(is_fatal_error(line), "{}", line);
+        }
+    }
+
+    #[test]
+    fn treats_network_errors_as_transient() {
+        for line in [
+            "ERROR: [youtube] dQw4w9WgXcQ: Unable to download API page: <urlopen error [Errno -3] \
+             Temporary failure in name resolution> (caused by TransportError)",
+            "ERROR: unable to download video data: HTTP Error 503: Service Unavailable",
+            "ERROR: [download] Got error: The read operation timed out",
+            "ERROR: [youtube] dQw4w9WgXcQ: Connection reset by peer",
+        ] {
+            assert!(!is_fatal_error(line), "{}", line);
+        }
+    }
+
+    #[test]
+    fn backs_off_up_to_the_limit() {
+        let mut options = test_options(5);
+        options.retry_delay = 10;
+        options.max_backoff = 300;
+        assert_eq!(retry_delay_for(&options, 3), Duration::from_secs(10));
+
+        options.backoff = true;
+        let first = retry_delay_for(&options, 1).as_secs_f64();
+        assert!((8.0..=12.0).contains(&first), "{}", first);
+        let third = retry_delay_for(&options, 3).as_secs_f64();
+        assert!((32.0..=48.0).contains(&third), "{}", third);
+        assert!(retry_delay_for(&options, 10) <= Duration::from_secs(300));
+    }
+
+    #[test]
+    fn matches_only_own_partial_files() {
+        assert!(is_partial_file_of("Intro.mp4.part", "Intro"));
+        assert!(is_partial_file_of("Intro.f137.mp4.part", "Intro"));
+        assert!(is_partial_file_of("Intro.mp4.ytdl", "Intro"));
+        assert!(is_partial_file_of("Intro.mp4.part-Frag12", "Intro"));
+        assert!(!is_partial_file_of("Intro 2.mp4.part", "Intro"));
+        assert!(!is_partial_file_of("Intro.mp4", "Intro"));
+    }
+
+    #[test]
+    fn classifies_urls() {
+        for (url, kind) in [
+            ("https://www.youtube.com/watch?v=dQw4w9WgXcQ", UrlKind::Video),
+            ("https://www.youtube.com/playlist?list=PL123", UrlKind::Playlist),
+            ("https://www.youtube.com/shorts/dQw4w9WgXcQ", UrlKind::Shorts),
+            ("https://youtu.be/dQw4w9WgXcQ", UrlKind::ShortLink),
+            ("https://www.youtube.com/@somechannel", UrlKind::Channel),
+            ("https://www.youtube.com/@somechannel/videos", UrlKind::Channel),
+            ("https://www.youtube.com/channel/UC123", UrlKind::Channel),
+            ("https://www.youtube.com/c/SomeChannel", UrlKind::Channel),
+            ("https://www.youtube.com/feed/trending", UrlKind::Unknown),
+            ("https://vimeo.com/76979871", UrlKind::Unknown),
+            ("htps://youtu.be/dQw4w9WgXcQ", UrlKind::Unknown),
+        ] {
+            assert_eq!(classify_url(url), kind, "{}", url);
+        }
+    }
+
+    #[test]
+    fn removes_failed_videos_from_the_archive() {
+        let line = "[info] dQw4w9WgXcQ: Downloading 1 format(s): 137+140";
+        assert_eq!(downloaded_video_id(line), Some("dQw4w9WgXcQ"));
+        assert_eq!(downloaded_video_id("[info] Writing video description"), None);
+
+        let archive = std::env::temp_dir().join(format!("ytdl-archive-{}.txt", std::process::id()));
+        std::fs::write(&archive, "youtube aaaaaaaaaaa\nyoutube dQw4w9WgXcQ\nvimeo 76979871\n")
+            .unwrap();
+        remove_from_archive(&archive, "dQw4w9WgXcQ").unwrap();
+        let contents = std::fs::read_to_string(&archive).unwrap();
+        std::fs::remove_file(&archive).unwrap();
+        assert_eq!(contents, "youtube aaaaaaaaaaa\nvimeo 76979871\n");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unplayable_playlist_video_leaves_the_others_archived() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = std::env::temp_dir().join(format!("ytdl-verify-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let yt_dlp = dir.join("yt-dlp");
+        std::fs::write(
+            &yt_dlp,
+            concat!(
+                "#!/bin/sh\n",
+                "echo '[info] aaaaaaaaaaa: Downloading 1 format(s): 18'\n",
+                "echo '[download] Destination: good.mp4'\n",
+                "echo '[info] bbbbbbbbbbb: Downloading 1 format(s): 18'\n",
+                "echo '[download] Destination: broken.mp4'\n",
+            ),
+        )
+        .unwrap();
+        let ffprobe = dir.join("ffprobe");
+        std::fs::write(
+            &ffprobe,
+            concat!(
+                "#!/bin/sh\n",
+                "case \"$*\" in *broken.mp4) exit 1;; esac\n",
+                "echo '{\"streams\": [{\"codec_type\": \"video\"}], ",
+                "\"format\": {\"duration\": \"10.0\"}}'\n",
+            ),
+        )
+        .unwrap();
+        for script in [&yt_dlp, &ffprobe] {
+            std::fs::set_permissions(script, std::fs::Permissions::from_mode(0o755)).unwrap();
+        }
+        let archive = dir.join("archive.txt");
+        std::fs::write(&archive, "youtube aaaaaaaaaaa\nyoutube bbbbbbbbbbb\n").unwrap();
+        let mut options = test_options(0);
+        options.yt_dlp_path = yt_dlp;
+        options.ffmpeg_path = dir.join("ffmpeg");
+        options.output = dir.clone();
+        options.archive = Some(archive.clone());
+        options.verify = true;
+        options.quiet = true;
+
+        let result = download_video(&options, URL, &MultiProgress::new());
+        let contents = std::fs::read_to_string(&archive).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let error = result.unwrap_err();
+        assert!(matches!(error.downcast_ref(), Some(DownloadError::Unplayable(_))), "{:#}", error);
+        assert_eq!(contents, "youtube aaaaaaaaaaa\n");
+    }
+
+    #[test]
+    fn parses_playlist_item_lines() {
+        assert_eq!(playlist_item("[download] Downloading item 3 of 12"), Some((3, 12)));
+        assert_eq!(playlist_item("[download] Downloading video 1 of 2"), Some((1, 2)));
+        assert_eq!(playlist_item("[download] Downloading playlist: Mix"), None);
+        assert_eq!(playlist_item("[download]  42.0% of 10.00MiB"), None);
+    }
+
+    #[test]
+    fn reads_download_progress() {
+        let options = test_options(0);
+        let reporter = ProgressReporter::new(&options, URL, &MultiProgress::new());
+        assert!(reporter.update("[download]  42.4% of 10.00MiB at  1.20MiB/s ETA 00:05"));
+        assert_eq!(reporter.pb.position(), 42);
+        assert_eq!(reporter.pb.message(), "1.20MiB/s / ETA 00:05");
+        assert!(reporter.update("[download] 100.0% of 10.00MiB at Unknown speed ETA Unknown"));
+        assert_eq!(reporter.pb.position(), 100);
+        assert!(reporter.update("[download] 5.00MiB at  2.00MiB/s (00:03)"));
+        assert_eq!(reporter.pb.message(), "Live: 5.00MiB at 2.00MiB/s (00:03)");
+        assert!(!reporter.update("[youtube] dQw4w9WgXcQ: Downloading webpage"));
+    }
+
+    #[test]
+    fn tracks_final_output_files() {
+        let files = Mutex::new(Vec::new());
+        for line in [
+            "[download] Destination: out/Song.f137.mp4",
+            "[download] Destination: out/Song.f140.m4a",
+            r#"[Merger] Merging formats into "out/Song.mp4""#,
+            "[download] out/Old.webm has already been downloaded",
+            "[info] Writing video description to: out/Song.description",
+        ] {
+            track_output_file(line, None, &files);
+        }
+        let files = files.into_inner().unwrap();
+        let paths: Vec<_> = files.iter().map(|file| file.path.to_str().unwrap()).collect();
+        assert_eq!(paths, ["out/Song.mp4", "out/Old.webm"]);
+        assert!(files[0].merged && !files[0].skipped);
+        assert!(files[1].skipped && !files[1].merged);
+    }
+
+    #[test]
+    fn tracks_files_moved_out_of_the_temp_directory() {
+        let files = Mutex::new(Vec::new());
+        track_output_file("[download] Destination: tmp/Talk.webm", None, &files);
+        track_output_file("[ExtractAudio] Destination: tmp/Talk.opus", None, &files);
+        let line = r#"[MoveFiles] Moving file "tmp/Talk.opus" to "out/Talk.opus""#;
+        track_output_file(line, None, &files);
+        let files = files.into_inner().unwrap();
+        let paths: Vec<_> = files.iter().map(|file| file.path.to_str().unwrap()).collect();
+        assert_eq!(paths, ["out/Talk.opus"]);
+    }
+
+    #[test]
+    fn detects_playlists() {
+        assert!(is_playlist_url("https://www.youtube.com/playlist?list=PL123"));
+        assert!(is_playlist_url("https://www.youtube.com/watch?v=abc&list=PL123"));
+        assert!(!is_playlist_url("https://youtu.be/dQw4w9WgXcQ"));
+    }
+}
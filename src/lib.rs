@@ -0,0 +1,17 @@
+//! Core of the YouTube downloader: running yt-dlp and keeping yt-dlp and ffmpeg up to date.
+//! The `youtubedownloader` binary is a command-line shell around these modules.
+//!
+//! Other programs can embed the downloader by filling in a [`DownloadOptions`] and calling
+//! [`download_video_robust`] (or [`download_batch`] for several URLs). The most used items
+//! are re-exported here; the modules hold the rest.
+
+pub mod downloader;
+pub mod updater;
+pub mod version;
+
+pub use downloader::{
+    check_url, download_batch, download_video, download_video_robust, is_valid_url, BatchResult,
+    DownloadError, DownloadOptions, DownloadOutcome,
+};
+pub use updater::{bootstrap_yt_dlp, update_ffmpeg, update_yt_dlp};
+pub use version::parse_version;
@@ -0,0 +1,129 @@
+//! Logging to stderr and, with `--log-file`, to a size-rotated log file.
+
+use anyhow::{Context, Result};
+use log::{LevelFilter, Log, Metadata, Record};
+use std::fs::{File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::SystemTime;
+use youtubedownloader::downloader::current_url;
+
+/// Size at which the log file is rotated.
+const MAX_LOG_FILE_SIZE: u64 = 10 * 1024 * 1024;
+
+/// Number of rotated log files kept next to the current one (`.1` is the newest).
+const KEPT_LOG_FILES: u32 = 3;
+
+/// A log file that is renamed to `<path>.1` once it reaches `MAX_LOG_FILE_SIZE`, shifting
+/// older files up to `<path>.<KEPT_LOG_FILES>`.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("Failed to open log file {}", path.display()))?;
+        let size = file.metadata().map(|m| m.len()).unwrap_or(0);
+        Ok(RotatingFile {
+            path: path.to_path_buf(),
+            file,
+            size,
+        })
+    }
+
+    fn rotated_path(&self, index: u32) -> PathBuf {
+        let mut path = self.path.as_os_str().to_owned();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+
+    fn rotate(&mut self) -> Result<()> {
+        let _ = std::fs::remove_file(self.rotated_path(KEPT_LOG_FILES));
+        for index in (1..KEPT_LOG_FILES).rev() {
+            let _ = std::fs::rename(self.rotated_path(index), self.rotated_path(index + 1));
+        }
+        std::fs::rename(&self.path, self.rotated_path(1))
+            .with_context(|| format!("Failed to rotate log file {}", self.path.display()))?;
+        *self = RotatingFile::open(&self.path)?;
+        Ok(())
+    }
+
+    fn write_line(&mut self, line: &str) {
+        if self.size > 0 && self.size + line.len() as u64 > MAX_LOG_FILE_SIZE {
+            if let Err(e) = self.rotate() {
+                eprintln!("{:#}", e);
+            }
+        }
+        if self.file.write_all(line.as_bytes()).is_ok() {
+            self.size += line.len() as u64;
+        }
+    }
+}
+
+/// Sends every record to env_logger's stderr output and, if set, to the log file with a
+/// timestamp and the URL being downloaded.
+struct Logger {
+    stderr: env_logger::Logger,
+    file: Option<Mutex<RotatingFile>>,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata<'_>) -> bool {
+        self.stderr.enabled(metadata)
+    }
+
+    fn log(&self, record: &Record<'_>) {
+        if !self.stderr.matches(record) {
+            return;
+        }
+        self.stderr.log(record);
+        if let Some(file) = &self.file {
+            let url = current_url().map(|url| format!(" [{}]", url)).unwrap_or_default();
+            let line = format!(
+                "{} {:<5}{} {}: {}\n",
+                humantime::format_rfc3339_seconds(SystemTime::now()),
+                record.level(),
+                url,
+                record.target(),
+                record.args()
+            );
+            file.lock().unwrap().write_line(&line);
+        }
+    }
+
+    fn flush(&self) {
+        self.stderr.flush();
+        if let Some(file) = &self.file {
+            let _ = file.lock().unwrap().file.flush();
+        }
+    }
+}
+
+/// Sets up logging at the level chosen with `--quiet`/`--verbose`, letting RUST_LOG
+/// override it, and also writes the log to `log_file` if given.
+pub fn init(quiet: bool, verbose: u8, log_file: Option<&Path>) -> Result<()> {
+    let level = match (quiet, verbose) {
+        (true, _) => LevelFilter::Warn,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    let stderr = env_logger::Builder::new()
+        .filter_level(level)
+        .parse_default_env()
+        .build();
+    let file = match log_file {
+        Some(path) => Some(Mutex::new(RotatingFile::open(path)?)),
+        None => None,
+    };
+    log::set_max_level(stderr.filter());
+    log::set_boxed_logger(Box::new(Logger { stderr, file }))
+        .context("Failed to install the logger")
+}
@@ -1,30 +1,85 @@
+mod logging;
+
 use anyhow::{Context, Result};
-use clap::Parser;
-use indicatif::{ProgressBar, ProgressStyle};
+use clap::parser::ValueSource;
+use clap::{ArgAction, ArgMatches, CommandFactory, FromArgMatches, Parser};
+use indicatif::MultiProgress;
 use log::{error, info, warn};
 use regex::Regex;
-use reqwest::blocking::Client;
-use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, USER_AGENT};
+use serde::{Deserialize, Serialize};
 use serde_json::Value;
 use std::env;
-use std::io::{self, BufRead, BufReader, Read, Write};
-use std::path::{Path, PathBuf};
-use std::process::{Command, Stdio};
-use std::thread;
-use std::time::Duration;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Mutex, OnceLock};
+use std::time::{Duration, Instant};
 use url::Url;
-use std::io::Cursor;
-use zip::ZipArchive;
+use youtubedownloader::downloader::{
+    available_formats, check_url, classify_url, download_batch, download_video_robust,
+    ffprobe_path, format_size, format_sort_field_name, is_playlist_url, kill_running,
+    list_formats, normalize_url, remove_partial_files, request_stop, resolve_user_agent,
+    stop_requested, video_info, BatchResult, DownloadOptions, DownloadOutcome, UrlKind,
+};
+use youtubedownloader::updater::{
+    bootstrap_yt_dlp, missing_ffmpeg_features, update_ffmpeg, update_yt_dlp, ReleaseCache,
+    YT_DLP_CHANNELS,
+};
+
+/// Default yt-dlp location, relative to the executable directory.
+const DEFAULT_YT_DLP_PATH: &str = if cfg!(windows) { "yt-dlp.exe" } else { "yt-dlp" };
+
+/// Default ffmpeg location, relative to the executable directory.
+const DEFAULT_FFMPEG_PATH: &str = if cfg!(windows) {
+    "ffmpeg/ffmpeg.exe"
+} else {
+    "ffmpeg/ffmpeg"
+};
+
+/// Set while `prompt_user` waits for input. Nothing is downloading then, so Ctrl-C exits.
+static AT_PROMPT: AtomicBool = AtomicBool::new(false);
+
+/// Name of the `--resume-batch` state file, kept in the output directory.
+const BATCH_STATE_FILE: &str = ".ytdl-batch-state.json";
+
+/// Fields yt-dlp's --format-sort understands.
+const FORMAT_SORT_FIELDS: &[&str] = &[
+    "hasvid", "hasaud", "ie_pref", "lang", "quality", "source", "proto", "vcodec", "acodec",
+    "codec", "vext", "aext", "ext", "filesize", "fs_approx", "size", "height", "width", "res",
+    "fps", "hdr", "channels", "tbr", "vbr", "abr", "br", "asr", "id",
+];
+
+/// SponsorBlock segment categories understood by yt-dlp.
+const SPONSORBLOCK_CATEGORIES: &[&str] = &[
+    "sponsor",
+    "intro",
+    "outro",
+    "selfpromo",
+    "preview",
+    "filler",
+    "interaction",
+    "music_offtopic",
+    "poi_highlight",
+    "chapter",
+    "all",
+];
 
 #[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
+#[command(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "Option values are taken from the command line first, then from the config \
+                  file, and finally from the built-in defaults."
+)]
 struct Args {
     /// Path to the yt-dlp binary.
-    #[arg(long, value_name = "PATH", default_value = "yt-dlp.exe")]
+    #[arg(long, value_name = "PATH", default_value = DEFAULT_YT_DLP_PATH)]
     yt_dlp_path: PathBuf,
 
     /// Path to the ffmpeg binary.
-    #[arg(long, value_name = "PATH", default_value = "ffmpeg/ffmpeg.exe")]
+    #[arg(long, value_name = "PATH", default_value = DEFAULT_FFMPEG_PATH)]
     ffmpeg_path: PathBuf,
 
     /// Output directory for downloaded videos.
@@ -33,227 +88,898 @@ struct Args {
     #[arg(long, value_name = "PATH", default_value = "downloaded_videos")]
     output: PathBuf,
 
+    /// yt-dlp output template for file names, relative to the output directory, e.g.
+    /// "%(uploader)s/%(upload_date)s - %(title)s.%(ext)s" (default is "%(title)s.%(ext)s").
+    #[arg(
+        long,
+        value_name = "TEMPLATE",
+        default_value = "%(title)s.%(ext)s",
+        value_parser = parse_output_template
+    )]
+    output_template: String,
+
+    /// Save downloads in a subdirectory of the output directory named after today's date
+    /// (in UTC), or with "upload" after each video's upload date, e.g.
+    /// downloaded_videos/2024-06-01/. The directories are created as needed.
+    #[arg(
+        long,
+        value_name = "DATE",
+        num_args = 0..=1,
+        default_missing_value = "today",
+        value_parser = ["today", "upload"]
+    )]
+    date_subdirs: Option<String>,
+
+    /// strftime-style format of the --date-subdirs directory names (default is %Y-%m-%d).
+    /// A "/" nests directories, e.g. %Y/%m.
+    #[arg(
+        long,
+        value_name = "FORMAT",
+        default_value = "%Y-%m-%d",
+        value_parser = parse_date_format
+    )]
+    date_format: String,
+
+    /// Limit file names to ASCII letters, digits, '-', '_' and '.', replacing spaces and
+    /// everything else. Applies to the values filled into --output-template, not to the
+    /// literal text of the template itself.
+    #[arg(long)]
+    restrict_filenames: bool,
+
+    /// Replace only the characters Windows forbids in file names (such as : ? | and
+    /// trailing dots). Like --restrict-filenames, this affects the filled-in values of
+    /// --output-template; literal template text is kept as written.
+    #[arg(long)]
+    windows_filenames: bool,
+
+    /// Record downloaded video IDs in this archive file and skip videos already listed in it.
+    /// Without a path, "archive.txt" in the output directory is used.
+    #[arg(long, visible_alias = "archive-file", value_name = "PATH", num_args = 0..=1)]
+    archive: Option<Option<PathBuf>>,
+
     /// Automatically check for yt-dlp and ffmpeg updates on startup.
     #[arg(long)]
     update: bool,
 
+    /// Only report whether newer yt-dlp and ffmpeg versions exist, without installing
+    /// them. Useful when the binaries are installed read-only.
+    #[arg(long)]
+    check_only: bool,
+
+    /// Reuse the result of an update check made less than this long ago (default is 24h),
+    /// e.g. 30m, 6h or 7d. Results are cached in update-check.json next to the executable,
+    /// which keeps repeated runs under GitHub's API rate limit.
+    #[arg(long, value_name = "DURATION", default_value = "24h", value_parser = parse_interval)]
+    update_interval: Duration,
+
+    /// Ask GitHub for the latest versions even if a recent update check is cached.
+    #[arg(long)]
+    force_update_check: bool,
+
+    /// GitHub token for the update checks and --auto-install, which raises GitHub's API
+    /// rate limit from 60 to 5000 requests an hour (useful on shared CI runners).
+    #[arg(long, value_name = "TOKEN", env = "GITHUB_TOKEN", hide_env_values = true)]
+    github_token: Option<String>,
+
+    /// Download the latest yt-dlp release if the binary is missing.
+    #[arg(long)]
+    auto_install: bool,
+
+    /// yt-dlp release channel used by --update and --auto-install (default is stable).
+    /// Nightly and master builds get fixes for site changes sooner.
+    #[arg(
+        long,
+        visible_alias = "channel",
+        value_name = "CHANNEL",
+        default_value = "stable",
+        value_parser = ["stable", "nightly", "master"]
+    )]
+    release_channel: String,
+
     /// If provided, run in non-interactive mode and download these URLs.
     #[arg(name = "URLS", num_args = 0..)]
     urls: Vec<String>,
 
+    /// Read additional URLs from a file, one per line ("-" reads from stdin). Blank lines
+    /// and lines starting with '#' are ignored.
+    #[arg(long, value_name = "PATH")]
+    batch_file: Option<PathBuf>,
+
+    /// Skip the URLs that an earlier, unfinished batch run already downloaded. Progress is
+    /// kept in .ytdl-batch-state.json in the output directory and removed once a batch
+    /// fully succeeds.
+    #[arg(long)]
+    resume_batch: bool,
+
+    /// Show a desktop notification with the number of succeeded and failed URLs once a
+    /// batch finishes. Where notifications aren't available the summary is only logged.
+    #[arg(long)]
+    notify: bool,
+
     /// Run in non-interactive mode (requires at least one URL).
     #[arg(long)]
     non_interactive: bool,
 
-    /// Retry delay in seconds (default is 10).
+    /// Accept URLs from any site yt-dlp supports, not just YouTube. URLs must still use
+    /// http or https.
+    #[arg(long, visible_alias = "allow-any-site")]
+    allow_any_host: bool,
+
+    /// Delay in seconds before the first retry (default is 10). With --backoff it doubles
+    /// after each failed attempt.
     #[arg(long, default_value = "10")]
     retry_delay: u64,
+
+    /// Maximum number of retries for a failed download before moving on. Without it,
+    /// transient failures are retried until the download succeeds.
+    #[arg(long)]
+    max_retries: Option<u32>,
+
+    /// Double the retry delay after each failed attempt (with ±20% jitter) instead of
+    /// waiting a constant --retry-delay.
+    #[arg(long)]
+    backoff: bool,
+
+    /// Upper limit in seconds for the retry delay when --backoff is used (default is 300).
+    /// It can't be shorter than --retry-delay.
+    #[arg(
+        long,
+        visible_alias = "max-retry-delay",
+        value_name = "SECS",
+        default_value = "300"
+    )]
+    max_backoff: u64,
+
+    /// Stop and retry a download when yt-dlp makes no progress for this many seconds, e.g.
+    /// on a stalled connection. A line repeated unchanged doesn't count as progress. Long
+    /// merges can also be silent, so keep this generous.
+    #[arg(
+        long,
+        visible_alias = "download-timeout",
+        value_name = "SECS",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    timeout: Option<u64>,
+
+    /// Maximum download rate in bytes per second, with an optional K, M or G suffix
+    /// (e.g. 500K, 2M). The limit applies to each download, so with --concurrency the
+    /// total rate can be that many times higher. It is shared by the fragments of a download.
+    #[arg(long, value_name = "RATE", value_parser = parse_rate)]
+    limit_rate: Option<String>,
+
+    /// Download a live stream from its start rather than from the current point.
+    #[arg(long)]
+    live_from_start: bool,
+
+    /// Wait for a scheduled live stream or premiere to start, checking again every this
+    /// many seconds. Without it, such URLs fail right away instead of being retried.
+    #[arg(long, value_name = "SECS", value_parser = clap::value_parser!(u64).range(1..))]
+    wait_for_video: Option<u64>,
+
+    /// Refuse to start a download when the output volume has less free space than this, in
+    /// bytes with an optional K, M or G suffix (e.g. 20G). The check runs before every URL and
+    /// playlist item; without this option, less than 1 GiB free only gives a warning.
+    #[arg(
+        long,
+        visible_alias = "min-free-space",
+        value_name = "BYTES",
+        value_parser = parse_size
+    )]
+    require_free_space: Option<u64>,
+
+    /// Number of fragments of a DASH/HLS video yt-dlp downloads in parallel (default is 1).
+    /// --limit-rate still caps the combined rate of all fragments.
+    #[arg(
+        long,
+        visible_alias = "fragments",
+        value_name = "N",
+        default_value = "1",
+        value_parser = clap::value_parser!(u64).range(1..)
+    )]
+    concurrent_fragments: u64,
+
+    /// Number of videos to download in parallel in non-interactive mode (default is 1).
+    #[arg(
+        long,
+        short = 'j',
+        visible_alias = "jobs",
+        default_value = "1",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    concurrency: usize,
+
+    /// Extract only the audio track instead of downloading the video.
+    #[arg(long)]
+    audio_only: bool,
+
+    /// Audio format used with --audio-only (default is mp3).
+    #[arg(long, default_value = "mp3", value_parser = ["mp3", "m4a", "opus", "flac"])]
+    audio_format: String,
+
+    /// Container the video and audio streams are merged into (default is mp4). mkv accepts
+    /// any codecs; mp4 and webm prefer streams that fit the container.
+    #[arg(long, default_value = "mp4", value_parser = ["mp4", "mkv", "webm"])]
+    container: String,
+
+    /// Maximum video height to download (e.g. 480, 1080), or "best"/"worst" (default is 720).
+    #[arg(long, default_value = "720", value_parser = parse_quality)]
+    quality: String,
+
+    /// Rank formats with yt-dlp's --format-sort fields instead of preferring streams that
+    /// fit the container, e.g. "fps,vcodec:vp9". A --quality height is sorted first as a
+    /// preference (res:HEIGHT) rather than a hard limit, unless a res field is given.
+    #[arg(long, value_name = "FIELDS", value_parser = parse_format_sort)]
+    format_sort: Option<String>,
+
+    /// Download only the video itself when the URL also references a playlist.
+    #[arg(long)]
+    no_playlist: bool,
+
+    /// Keep the list= parameter of YouTube video links. Video links are otherwise rewritten
+    /// to their plain watch?v=ID form, which drops the playlist along with tracking
+    /// parameters such as si= and feature=.
+    #[arg(long, conflicts_with = "no_playlist")]
+    keep_playlist: bool,
+
+    /// Playlist items to download, using yt-dlp's syntax (e.g. "5-10", "1,3,7", "::2").
+    /// Ignored for single-video URLs.
+    #[arg(long, value_name = "ITEMS")]
+    playlist_items: Option<String>,
+
+    /// Download only this time range of the video, e.g. "*00:01:30-00:02:45" or "*90-inf".
+    /// Can be given several times for multiple sections.
+    #[arg(
+        long,
+        visible_alias = "download-sections",
+        value_name = "RANGE",
+        value_parser = parse_section
+    )]
+    section: Vec<String>,
+
+    /// Embed the video metadata and thumbnail into the output file.
+    #[arg(long)]
+    embed_metadata: bool,
+
+    /// Save the video description next to the video as a .description file.
+    #[arg(long)]
+    write_description: bool,
+
+    /// Save the video metadata next to the video as an .info.json file.
+    #[arg(long)]
+    write_info_json: bool,
+
+    /// Check each downloaded file with ffprobe (next to ffmpeg) and retry the download if
+    /// it has no audio or video stream or no duration.
+    #[arg(long)]
+    verify: bool,
+
+    /// Don't set the file modification time to the video's upload date.
+    #[arg(long, conflicts_with = "mtime_now")]
+    no_mtime: bool,
+
+    /// Set the file modification time to when the download finished, so new downloads sort
+    /// as recently added.
+    #[arg(long)]
+    mtime_now: bool,
+
+    /// User agent sent by yt-dlp. Use "chrome" or "firefox" for a built-in current browser
+    /// string; defaults to a Firefox 91 user agent.
+    #[arg(long, value_name = "UA")]
+    user_agent: Option<String>,
+
+    /// Don't send the built-in Firefox 91 user agent and browser headers, and let yt-dlp use
+    /// its own defaults. The built-in values are dated and can trigger bot detection; an
+    /// explicit --user-agent is still sent.
+    #[arg(long)]
+    no_default_headers: bool,
+
+    /// Route yt-dlp and the update checks through this proxy, e.g. socks5://127.0.0.1:1080.
+    /// Supports http://, https:// and socks5:// URLs. Without it, both honor the standard
+    /// HTTP_PROXY, HTTPS_PROXY and NO_PROXY environment variables.
+    #[arg(long, value_name = "URL", value_parser = parse_proxy)]
+    proxy: Option<String>,
+
+    /// Download manual and auto-generated subtitles as SRT files next to the video.
+    #[arg(long)]
+    subs: bool,
+
+    /// Download auto-generated subtitles only (these are also included by --subs).
+    #[arg(long)]
+    auto_subs: bool,
+
+    /// Embed the subtitles into the video file (requires an mp4 or mkv video, not --audio-only).
+    #[arg(long)]
+    embed_subs: bool,
+
+    /// Comma-separated subtitle languages, or "all" (default is en).
+    #[arg(long, value_name = "LANGS", default_value = "en")]
+    sub_langs: String,
+
+    /// Cut these SponsorBlock segment categories out of the video, e.g. sponsor,selfpromo
+    /// or all. Prefix a category with - to exclude it (all,-filler).
+    #[arg(long, value_name = "CATS", value_parser = parse_sponsorblock_categories)]
+    sponsorblock_remove: Option<String>,
+
+    /// Mark these SponsorBlock segment categories as chapters instead of removing them.
+    #[arg(long, value_name = "CATS", value_parser = parse_sponsorblock_categories)]
+    sponsorblock_mark: Option<String>,
+
+    /// Write a JSON summary of the batch results to this file.
+    #[arg(long, value_name = "PATH")]
+    report: Option<PathBuf>,
+
+    /// Print progress as newline-delimited JSON events on stdout instead of a progress bar,
+    /// followed by a {"url", "status", "file"} event for each downloaded file or failed URL.
+    #[arg(long, visible_alias = "json")]
+    progress_json: bool,
+
+    /// Print the yt-dlp command for each URL instead of running it. Update checks and
+    /// output directory creation still run as usual.
+    #[arg(long)]
+    dry_run: bool,
+
+    /// Show the title, duration, uploader, upload date, views and available resolutions of
+    /// each URL and exit without downloading.
+    #[arg(long, conflicts_with = "list_formats")]
+    info: bool,
+
+    /// List the available formats for each URL (like yt-dlp -F) and exit without downloading.
+    #[arg(long, visible_alias = "format-list")]
+    list_formats: bool,
+
+    /// Netscape-format cookies file passed to yt-dlp, for age-restricted or members-only videos.
+    #[arg(long, value_name = "FILE", conflicts_with = "cookies_from_browser")]
+    cookies: Option<PathBuf>,
+
+    /// Load cookies from the given browser (e.g. firefox, chrome) instead of a cookies file.
+    #[arg(long, value_name = "BROWSER")]
+    cookies_from_browser: Option<String>,
+
+    /// Command to run after each downloaded file, with {} standing for the file's path
+    /// (e.g. --exec "mv {} /media/inbox"). The path is passed quoted and never run, so {}
+    /// needs no quotes of its own ('{}' works too). Files that were already downloaded are
+    /// left alone. A failing command is logged but does not fail the download unless
+    /// --exec-strict is given.
+    #[arg(long, value_name = "CMD")]
+    exec: Option<String>,
+
+    /// Count the download as failed when the --exec command exits with an error.
+    #[arg(long, requires = "exec")]
+    exec_strict: bool,
+
+    /// Before downloading, resolve each URL's file name and skip it if a non-empty file
+    /// with that name is already in the output directory.
+    #[arg(long)]
+    skip_existing: bool,
+
+    /// Don't resume partially downloaded files: delete a URL's leftover .part and .ytdl
+    /// files and download it from the start. Helps when a corrupted partial file makes
+    /// every retry fail.
+    #[arg(long)]
+    no_resume: bool,
+
+    /// Extra arguments passed to yt-dlp unchanged, given after `--` (e.g. `-- --no-mtime`).
+    /// They are inserted just before the URL and are not validated.
+    #[arg(last = true, value_name = "YT_DLP_ARGS")]
+    yt_dlp_args: Vec<String>,
+
+    /// Path to a TOML config file with default option values (default is config.toml next
+    /// to the executable). Options given on the command line override the config file.
+    #[arg(long, value_name = "PATH")]
+    config: Option<PathBuf>,
+
+    /// Only log warnings and errors, and hide yt-dlp's output apart from the progress bar,
+    /// warnings and errors.
+    #[arg(long, short = 'q', conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// Log more detail: -v for debug output, -vv for trace output and yt-dlp's own verbose
+    /// output. RUST_LOG, if set, takes precedence.
+    #[arg(long, short = 'v', action = ArgAction::Count)]
+    verbose: u8,
+
+    /// Also append the log, with timestamps, to this file. It is rotated at 10 MB and the
+    /// last 3 rotated files are kept.
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
 }
 
-/// Parses a version string assumed to be in the "YYYY.MM.DD" format.
-fn parse_version(s: &str) -> Option<(u32, u32, u32)> {
-    let re = Regex::new(r"^\s*(\d{4})\.(\d{1,2})\.(\d{1,2})").ok()?;
-    let caps = re.captures(s)?;
-    let year = caps.get(1)?.as_str().parse::<u32>().ok()?;
-    let month = caps.get(2)?.as_str().parse::<u32>().ok()?;
-    let day = caps.get(3)?.as_str().parse::<u32>().ok()?;
-    Some((year, month, day))
+/// Option defaults loaded from the config file. Keys mirror the long option names with
+/// underscores, e.g. `yt_dlp_path = "bin/yt-dlp"`.
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct Config {
+    yt_dlp_path: Option<PathBuf>,
+    ffmpeg_path: Option<PathBuf>,
+    output: Option<PathBuf>,
+    output_template: Option<String>,
+    date_subdirs: Option<String>,
+    date_format: Option<String>,
+    archive: Option<PathBuf>,
+    restrict_filenames: Option<bool>,
+    windows_filenames: Option<bool>,
+    update: Option<bool>,
+    check_only: Option<bool>,
+    update_interval: Option<String>,
+    auto_install: Option<bool>,
+    release_channel: Option<String>,
+    batch_file: Option<PathBuf>,
+    resume_batch: Option<bool>,
+    notify: Option<bool>,
+    non_interactive: Option<bool>,
+    allow_any_host: Option<bool>,
+    retry_delay: Option<u64>,
+    max_retries: Option<u32>,
+    backoff: Option<bool>,
+    max_backoff: Option<u64>,
+    timeout: Option<u64>,
+    limit_rate: Option<String>,
+    live_from_start: Option<bool>,
+    wait_for_video: Option<u64>,
+    require_free_space: Option<String>,
+    concurrent_fragments: Option<u64>,
+    concurrency: Option<usize>,
+    audio_only: Option<bool>,
+    audio_format: Option<String>,
+    container: Option<String>,
+    quality: Option<String>,
+    format_sort: Option<String>,
+    no_playlist: Option<bool>,
+    keep_playlist: Option<bool>,
+    playlist_items: Option<String>,
+    section: Option<Vec<String>>,
+    embed_metadata: Option<bool>,
+    write_description: Option<bool>,
+    write_info_json: Option<bool>,
+    verify: Option<bool>,
+    no_mtime: Option<bool>,
+    mtime_now: Option<bool>,
+    user_agent: Option<String>,
+    no_default_headers: Option<bool>,
+    proxy: Option<String>,
+    subs: Option<bool>,
+    auto_subs: Option<bool>,
+    embed_subs: Option<bool>,
+    sub_langs: Option<String>,
+    sponsorblock_remove: Option<String>,
+    sponsorblock_mark: Option<String>,
+    report: Option<PathBuf>,
+    progress_json: Option<bool>,
+    dry_run: Option<bool>,
+    yt_dlp_args: Option<Vec<String>>,
+    exec: Option<String>,
+    exec_strict: Option<bool>,
+    skip_existing: Option<bool>,
+    no_resume: Option<bool>,
+    cookies: Option<PathBuf>,
+    cookies_from_browser: Option<String>,
 }
 
-/// Checks for updates to yt-dlp by comparing the current version with the latest release on GitHub.
-fn update_yt_dlp(yt_dlp_path: &Path) -> Result<()> {
-    info!("Checking for yt-dlp updates...");
-    let output = Command::new(yt_dlp_path)
-        .arg("--version")
-        .output()
-        .with_context(|| format!("Failed to execute {:?} --version", yt_dlp_path))?;
+/// Reads the config file at `path`. A missing file is not an error and yields `None`.
+fn load_config(path: &Path) -> Result<Option<Config>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(e) => {
+            return Err(e).with_context(|| format!("Failed to read config file {}", path.display()))
+        }
+    };
+    let config = toml::from_str(&contents)
+        .with_context(|| format!("Failed to parse config file {}", path.display()))?;
+    info!("Loaded config from {}", path.display());
+    Ok(Some(config))
+}
 
-    if !output.status.success() {
-        warn!("Failed to retrieve current yt-dlp version.");
-        return Ok(());
+/// Fills in every option that was not given on the command line from `config`.
+fn apply_config(args: &mut Args, config: Config, matches: &ArgMatches) -> Result<()> {
+    let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    macro_rules! merge {
+        ($($field:ident),* $(,)?) => {
+            $(
+                if let Some(value) = config.$field {
+                    if !from_cli(stringify!($field)) {
+                        args.$field = value;
+                    }
+                }
+            )*
+        };
     }
-    let current_version = String::from_utf8_lossy(&output.stdout).trim().to_string();
-    info!("Current yt-dlp version: {}", current_version);
-
-    let mut headers = HeaderMap::new();
-    headers.insert(USER_AGENT, HeaderValue::from_static("RustClient/1.0"));
-    headers.insert(ACCEPT, HeaderValue::from_static("application/vnd.github.v3+json"));
-
-    let client = Client::builder()
-        .default_headers(headers)
-        .build()
-        .context("Failed to build HTTP client")?;
-    let response = client
-        .get("https://api.github.com/repos/yt-dlp/yt-dlp/releases/latest")
-        .send()
-        .context("Failed to send request to GitHub API")?;
-    if !response.status().is_success() {
-        warn!(
-            "Failed to fetch the latest yt-dlp version info. HTTP Status: {}",
-            response.status()
-        );
-        return Ok(());
+    macro_rules! merge_optional {
+        ($($field:ident),* $(,)?) => {
+            $(
+                if config.$field.is_some() && !from_cli(stringify!($field)) {
+                    args.$field = config.$field;
+                }
+            )*
+        };
     }
-    let json: Value = response.json().context("Failed to parse JSON from GitHub API")?;
-    let latest_version = json["tag_name"].as_str().unwrap_or("").trim().to_string();
-    if latest_version.is_empty() {
-        warn!("Could not parse the latest version info.");
-        return Ok(());
+    merge!(
+        yt_dlp_path,
+        ffmpeg_path,
+        output,
+        output_template,
+        date_format,
+        restrict_filenames,
+        windows_filenames,
+        update,
+        check_only,
+        auto_install,
+        release_channel,
+        resume_batch,
+        notify,
+        non_interactive,
+        allow_any_host,
+        retry_delay,
+        backoff,
+        max_backoff,
+        live_from_start,
+        concurrent_fragments,
+        concurrency,
+        audio_only,
+        audio_format,
+        container,
+        quality,
+        no_playlist,
+        keep_playlist,
+        embed_metadata,
+        write_description,
+        write_info_json,
+        verify,
+        no_mtime,
+        mtime_now,
+        no_default_headers,
+        subs,
+        auto_subs,
+        embed_subs,
+        sub_langs,
+        progress_json,
+        dry_run,
+        yt_dlp_args,
+        section,
+        exec_strict,
+        skip_existing,
+        no_resume,
+    );
+    merge_optional!(
+        max_retries,
+        user_agent,
+        proxy,
+        timeout,
+        wait_for_video,
+        batch_file,
+        playlist_items,
+        format_sort,
+        limit_rate,
+        report,
+        cookies,
+        cookies_from_browser,
+        sponsorblock_remove,
+        sponsorblock_mark,
+        exec,
+        date_subdirs,
+    );
+    if config.archive.is_some() && !from_cli("archive") {
+        args.archive = Some(config.archive);
+    }
+    if let Some(size) = &config.require_free_space {
+        if !from_cli("require_free_space") {
+            args.require_free_space = Some(parse_size(size).map_err(anyhow::Error::msg)?);
+        }
+    }
+    if let Some(interval) = &config.update_interval {
+        if !from_cli("update_interval") {
+            args.update_interval = parse_interval(interval).map_err(anyhow::Error::msg)?;
+        }
     }
-    info!("Latest yt-dlp version: {}", latest_version);
-
-    let need_update = if let (Some(current_parsed), Some(latest_parsed)) =
-        (parse_version(&current_version), parse_version(&latest_version))
-    {
-        current_parsed < latest_parsed
-    } else {
-        current_version != latest_version
-    };
 
-    if need_update {
-        info!("A newer yt-dlp version is available. Updating yt-dlp...");
-        let status = Command::new(yt_dlp_path)
-            .arg("-U")
-            .status()
-            .with_context(|| format!("Failed to execute {:?} -U", yt_dlp_path))?;
-        if status.success() {
-            info!("yt-dlp updated successfully.");
-        } else {
-            error!("yt-dlp update failed.");
+    // Values from the file bypass clap's parsers, so validate them here.
+    args.quality = parse_quality(&args.quality).map_err(anyhow::Error::msg)?;
+    args.output_template =
+        parse_output_template(&args.output_template).map_err(anyhow::Error::msg)?;
+    args.date_format = parse_date_format(&args.date_format).map_err(anyhow::Error::msg)?;
+    if let Some(date) = &args.date_subdirs {
+        if !["today", "upload"].contains(&date.as_str()) {
+            anyhow::bail!("invalid date_subdirs '{}' in config file", date);
         }
-    } else {
-        info!("The current yt-dlp is up-to-date.");
+    }
+    if args.concurrency == 0 {
+        anyhow::bail!("concurrency must be at least 1");
+    }
+    if args.concurrent_fragments == 0 {
+        anyhow::bail!("concurrent_fragments must be at least 1");
+    }
+    if args.timeout == Some(0) {
+        anyhow::bail!("timeout must be at least 1");
+    }
+    if args.wait_for_video == Some(0) {
+        anyhow::bail!("wait_for_video must be at least 1");
+    }
+    if let Some(rate) = &args.limit_rate {
+        args.limit_rate = Some(parse_rate(rate).map_err(anyhow::Error::msg)?);
+    }
+    if let Some(proxy) = &args.proxy {
+        args.proxy = Some(parse_proxy(proxy).map_err(anyhow::Error::msg)?);
+    }
+    for section in &mut args.section {
+        *section = parse_section(section).map_err(anyhow::Error::msg)?;
+    }
+    if let Some(sort) = &args.format_sort {
+        args.format_sort = Some(parse_format_sort(sort).map_err(anyhow::Error::msg)?);
+    }
+    let categories = [&mut args.sponsorblock_remove, &mut args.sponsorblock_mark];
+    for value in categories.into_iter().flatten() {
+        *value = parse_sponsorblock_categories(value).map_err(anyhow::Error::msg)?;
+    }
+    if !["mp3", "m4a", "opus", "flac"].contains(&args.audio_format.as_str()) {
+        anyhow::bail!("invalid audio_format '{}' in config file", args.audio_format);
+    }
+    if !["mp4", "mkv", "webm"].contains(&args.container.as_str()) {
+        anyhow::bail!("invalid container '{}' in config file", args.container);
+    }
+    if !YT_DLP_CHANNELS.contains(&args.release_channel.as_str()) {
+        anyhow::bail!("invalid release_channel '{}' in config file", args.release_channel);
+    }
+    if args.cookies.is_some() && args.cookies_from_browser.is_some() {
+        anyhow::bail!("cookies and cookies_from_browser cannot be used together");
+    }
+    if args.no_mtime && args.mtime_now {
+        anyhow::bail!("no_mtime and mtime_now cannot be used together");
+    }
+    if args.keep_playlist && args.no_playlist {
+        anyhow::bail!("keep_playlist and no_playlist cannot be used together");
+    }
+    if args.exec_strict && args.exec.is_none() {
+        anyhow::bail!("exec_strict requires exec");
     }
     Ok(())
 }
 
-fn update_ffmpeg(ffmpeg_path: &Path) -> Result<()> {
-    info!("Checking for ffmpeg updates...");
+/// Progress of a batch run, saved after every URL so `--resume-batch` can skip the URLs
+/// that were already downloaded.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct BatchState {
+    completed: Vec<String>,
+}
 
-    let output = Command::new(ffmpeg_path)
-        .arg("-version")
-        .output()
-        .with_context(|| format!("Failed to execute {:?} -version", ffmpeg_path))?;
+impl BatchState {
+    /// Reads the state file at `path`. A missing file yields an empty state.
+    fn load(path: &Path) -> Result<Self> {
+        match std::fs::read_to_string(path) {
+            Ok(json) => serde_json::from_str(&json)
+                .with_context(|| format!("Failed to parse batch state file {}", path.display())),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(BatchState::default()),
+            Err(e) => Err(e)
+                .with_context(|| format!("Failed to read batch state file {}", path.display())),
+        }
+    }
 
-    if !output.status.success() {
-        warn!("Failed to retrieve current ffmpeg version.");
-        return Ok(());
+    /// Marks the URL of `result` as completed and saves the state to `path`. Failed URLs are
+    /// left out so a resumed batch retries them, and a dry run, which downloads nothing,
+    /// doesn't touch the state at all.
+    fn record(&mut self, result: &BatchResult, path: &Path, dry_run: bool) -> Result<()> {
+        if dry_run || !result.success {
+            return Ok(());
+        }
+        self.completed.push(result.url.clone());
+        self.save(path)
     }
-    let current_version_output = String::from_utf8_lossy(&output.stdout);
-    let current_version_line = current_version_output.lines().next().unwrap_or("");
-    let current_version = current_version_line
-        .split_whitespace()
-        .nth(2)
-        .unwrap_or("")
-        .to_string();
-    info!("Current ffmpeg version: {}", current_version);
-
-    let mut headers = HeaderMap::new();
-    headers.insert(USER_AGENT, HeaderValue::from_static("RustClient/1.0"));
-    headers.insert(ACCEPT, HeaderValue::from_static("application/vnd.github.v3+json"));
-    let client = Client::builder()
-        .default_headers(headers)
-        .build()
-        .context("Failed to build HTTP client for ffmpeg update")?;
-
-    let response = client
-        .get("https://api.github.com/repos/BtbN/FFmpeg-Builds/releases/latest")
-        .send()
-        .context("Failed to send request to GitHub API for ffmpeg")?;
-    if !response.status().is_success() {
-        warn!(
-            "Failed to fetch the latest ffmpeg version info. HTTP Status: {}",
-            response.status()
-        );
-        return Ok(());
+
+    /// Writes the state to `path` through a temporary file, so a run killed mid-write
+    /// leaves the previous state intact.
+    fn save(&self, path: &Path) -> Result<()> {
+        let json = serde_json::to_string_pretty(self).context("Failed to serialize batch state")?;
+        let tmp_path = path.with_extension("json.tmp");
+        std::fs::write(&tmp_path, json)
+            .and_then(|()| std::fs::rename(&tmp_path, path))
+            .with_context(|| format!("Failed to write batch state file {}", path.display()))
     }
-    let json: Value =
-        response.json().context("Failed to parse JSON from GitHub API for ffmpeg")?;
-    let tag_name = json["tag_name"].as_str().unwrap_or("").trim().to_string();
-    if tag_name.is_empty() {
-        warn!("Could not parse the latest ffmpeg version info.");
-        return Ok(());
+}
+
+/// Reads URLs from a batch file, or from stdin when `path` is "-".
+fn read_batch_file(path: &Path) -> Result<Vec<String>> {
+    let contents = if path == Path::new("-") {
+        let mut contents = String::new();
+        io::stdin()
+            .read_to_string(&mut contents)
+            .context("Failed to read URLs from stdin")?;
+        contents
+    } else {
+        std::fs::read_to_string(path)
+            .with_context(|| format!("Failed to read batch file {}", path.display()))?
+    };
+    Ok(contents
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Validates a `--quality` value: a positive pixel height or one of "best"/"worst".
+fn parse_quality(s: &str) -> std::result::Result<String, String> {
+    let quality = s.trim().to_lowercase();
+    if quality == "best" || quality == "worst" {
+        return Ok(quality);
+    }
+    match quality.trim_end_matches('p').parse::<u32>() {
+        Ok(height) if height > 0 => Ok(height.to_string()),
+        _ => Err(format!(
+            "invalid quality '{}': expected a height such as 720 or 1080, or 'best'/'worst'",
+            s
+        )),
     }
-    info!("Latest ffmpeg version: {}", tag_name);
+}
 
-    if current_version == tag_name {
-        info!("The current ffmpeg is up-to-date.");
-        return Ok(());
+/// Validates a `--limit-rate` value: a number with an optional K, M or G suffix.
+fn parse_rate(s: &str) -> std::result::Result<String, String> {
+    let rate = s.trim();
+    let number = rate.trim_end_matches(['K', 'M', 'G', 'k', 'm', 'g']);
+    let suffix_len = rate.len() - number.len();
+    let numeric = number.chars().all(|c| c.is_ascii_digit() || c == '.');
+    match number.parse::<f64>() {
+        Ok(value) if numeric && suffix_len <= 1 && value > 0.0 => Ok(rate.to_uppercase()),
+        _ => Err(format!(
+            "invalid rate '{}': expected a number with an optional K, M or G suffix, e.g. 500K",
+            s
+        )),
     }
+}
 
-    info!("A newer ffmpeg version is available. Updating ffmpeg...");
+/// Parses an `--update-interval` value such as 30m, 6h or 7d.
+fn parse_interval(s: &str) -> std::result::Result<Duration, String> {
+    humantime::parse_duration(s.trim())
+        .map_err(|e| format!("invalid interval '{}': {}, expected e.g. 6h or 7d", s, e))
+}
 
-    let assets = json["assets"]
-        .as_array()
-        .ok_or_else(|| anyhow::anyhow!("No assets found in ffmpeg release JSON"))?;
-    let mut download_url = None;
-    for asset in assets {
-        if let Some(name) = asset["name"].as_str() {
-            if name.to_lowercase().contains("win64") && name.to_lowercase().ends_with(".zip") {
-                download_url = asset["browser_download_url"].as_str().map(|s| s.to_string());
-                break;
-            }
-        }
+/// Parses a `--require-free-space` value: a byte count with an optional K, M or G suffix
+/// (powers of 1024).
+fn parse_size(s: &str) -> std::result::Result<u64, String> {
+    let size = parse_rate(s)
+        .map_err(|_| format!("invalid size '{}': expected a number such as 500M or 20G", s))?;
+    let (number, multiplier) = match size.chars().last() {
+        Some('K') => (&size[..size.len() - 1], 1u64 << 10),
+        Some('M') => (&size[..size.len() - 1], 1 << 20),
+        Some('G') => (&size[..size.len() - 1], 1 << 30),
+        _ => (size.as_str(), 1),
+    };
+    let value: f64 = number.parse().map_err(|_| format!("invalid size '{}'", s))?;
+    Ok((value * multiplier as f64) as u64)
+}
+
+/// Validates a `--section` value: a `*START-END` time range where each time is in seconds
+/// or [HH:]MM:SS form, and END may be "inf".
+fn parse_section(s: &str) -> std::result::Result<String, String> {
+    static SECTION_REGEX: OnceLock<Regex> = OnceLock::new();
+    let section_regex = SECTION_REGEX.get_or_init(|| {
+        let time = r"\d+(?::\d{1,2}){0,2}(?:\.\d+)?";
+        Regex::new(&format!(r"^\*{time}-(?:{time}|inf)$")).unwrap()
+    });
+    let section = s.trim();
+    if section_regex.is_match(section) {
+        Ok(section.to_string())
+    } else {
+        Err(format!(
+            "invalid section '{}': expected a time range such as *00:01:30-00:02:45",
+            s
+        ))
     }
-    let download_url = match download_url {
-        Some(url) => url,
-        None => {
-            warn!("Could not find a suitable ffmpeg update asset for Windows 64-bit.");
-            return Ok(());
+}
+
+/// Validates a `--format-sort` value: comma-separated fields, each optionally prefixed
+/// with + to reverse it and followed by :VALUE or ~VALUE.
+fn parse_format_sort(s: &str) -> std::result::Result<String, String> {
+    let sort = s.trim().replace(' ', "");
+    for field in sort.split(',') {
+        if !FORMAT_SORT_FIELDS.contains(&format_sort_field_name(field)) {
+            return Err(format!(
+                "unknown format sort field '{}' in '{}': expected one of {}",
+                field,
+                s,
+                FORMAT_SORT_FIELDS.join(", ")
+            ));
         }
-    };
+    }
+    Ok(sort)
+}
 
-    info!("Downloading ffmpeg update from {}", download_url);
-    let resp = client
-        .get(&download_url)
-        .send()
-        .context("Failed to download ffmpeg update")?;
-    if !resp.status().is_success() {
-        error!(
-            "Failed to download ffmpeg update. HTTP Status: {}",
-            resp.status()
-        );
-        return Ok(());
+/// Validates a comma-separated list of SponsorBlock categories, each optionally prefixed
+/// with `-` to exclude it.
+fn parse_sponsorblock_categories(s: &str) -> std::result::Result<String, String> {
+    let categories = s.trim().to_lowercase();
+    let valid = categories.split(',').all(|category| {
+        let category = category.trim().trim_start_matches('-');
+        SPONSORBLOCK_CATEGORIES.contains(&category)
+    });
+    if valid {
+        Ok(categories.replace(' ', ""))
+    } else {
+        Err(format!(
+            "invalid SponsorBlock categories '{}': expected a comma-separated list of {}",
+            s,
+            SPONSORBLOCK_CATEGORIES.join(", ")
+        ))
     }
+}
 
-    let bytes = resp
-        .bytes()
-        .context("Failed to read ffmpeg update response bytes")?;
-    let reader = Cursor::new(bytes);
-    let mut zip_archive =
-        ZipArchive::new(reader).context("Failed to open zip archive for ffmpeg update")?;
-
-    let mut ffmpeg_data = None;
-    for i in 0..zip_archive.len() {
-        let mut file = zip_archive
-            .by_index(i)
-            .context("Failed to access file in zip archive")?;
-        let name = file.name().to_string();
-        if name.to_lowercase().ends_with("ffmpeg.exe") {
-            let mut buf = Vec::new();
-            file.read_to_end(&mut buf)
-                .context("Failed to read ffmpeg.exe from zip archive")?;
-            ffmpeg_data = Some(buf);
-            break;
-        }
-    }
-    let ffmpeg_data = match ffmpeg_data {
-        Some(data) => data,
-        None => {
-            warn!("ffmpeg.exe not found in the downloaded archive.");
-            return Ok(());
+/// Validates a `--proxy` value: an http, https or socks5 URL with a host.
+fn parse_proxy(s: &str) -> std::result::Result<String, String> {
+    match Url::parse(s.trim()) {
+        Ok(url)
+            if matches!(url.scheme(), "http" | "https" | "socks5") && url.host().is_some() =>
+        {
+            Ok(s.trim().to_string())
         }
-    };
+        _ => Err(format!(
+            "invalid proxy '{}': expected an http://, https:// or socks5:// URL",
+            s
+        )),
+    }
+}
 
-    std::fs::write(ffmpeg_path, ffmpeg_data)
-        .with_context(|| format!("Failed to write updated ffmpeg to {:?}", ffmpeg_path))?;
-    info!("ffmpeg updated successfully.");
-    Ok(())
+/// Validates an `--output-template` value. It must stay inside the output directory (no
+/// absolute paths or `..`) and end in an extension such as `.%(ext)s` so downloaded files
+/// don't lose their extension.
+fn parse_output_template(s: &str) -> std::result::Result<String, String> {
+    let path = Path::new(s);
+    let escapes = path.components().any(|c| {
+        matches!(
+            c,
+            Component::Prefix(_) | Component::RootDir | Component::ParentDir
+        )
+    });
+    if escapes {
+        return Err(format!(
+            "output template '{}' must be a relative path inside the output directory",
+            s
+        ));
+    }
+    if s.contains("%(ext)s") || path.extension().is_some() {
+        Ok(s.to_string())
+    } else {
+        Err(format!(
+            "output template '{}' has no extension; end it with '.%(ext)s'",
+            s
+        ))
+    }
 }
 
-/// Validates a URL.
-fn is_valid_url(url: &str) -> bool {
-    Url::parse(url).is_ok()
+/// Validates a `--date-format` value. It becomes part of a yt-dlp output template, so it
+/// can't close the template field with ')' or leave the output directory with "..".
+fn parse_date_format(s: &str) -> std::result::Result<String, String> {
+    let invalid = s.contains(')')
+        || s.split('/').all(|part| part.trim().is_empty())
+        || s.split('/').any(|part| part.trim() == "..");
+    if invalid {
+        Err(format!("invalid date format '{}': expected e.g. %Y-%m-%d or %Y/%m", s))
+    } else {
+        Ok(s.to_string())
+    }
+}
+
+/// Prefixes `template` with the `--date-subdirs` directories, letting yt-dlp format either
+/// `epoch` (when the video info was fetched) or `upload_date`. Each "/"-separated part of
+/// `format` becomes its own template field, since yt-dlp replaces slashes in field values.
+fn date_subdir_template(template: &str, date: &str, format: &str) -> String {
+    let field = if date == "upload" { "upload_date" } else { "epoch" };
+    let mut dirs = String::new();
+    for part in format.split('/').filter(|part| !part.trim().is_empty()) {
+        dirs.push_str(&format!("%({field}>{part})s/"));
+    }
+    dirs + template
+}
+
+/// Logs what kind of YouTube page `url` is, and warns before a whole channel is downloaded
+/// when `--playlist-items` doesn't limit it.
+fn log_url_kind(url: &str, limited: bool) {
+    let kind = classify_url(url);
+    info!("{} is a {}.", url, kind);
+    if kind == UrlKind::Channel && !limited {
+        warn!(
+            "{} is a channel: every video on it will be downloaded. \
+             Use --playlist-items (e.g. 1-10) to limit this.",
+            url
+        );
+    }
 }
 
 /// Helper function to prompt the user (used only in interactive mode).
@@ -261,137 +987,222 @@ fn prompt_user(prompt: &str) -> Result<String> {
     print!("{}", prompt);
     io::stdout().flush()?;
     let mut input = String::new();
-    io::stdin().read_line(&mut input)?;
+    AT_PROMPT.store(true, Ordering::SeqCst);
+    let read = io::stdin().read_line(&mut input);
+    AT_PROMPT.store(false, Ordering::SeqCst);
+    read?;
     Ok(input.trim().to_string())
 }
-/// It uses the resume flag (`-c`) and forces the output format to MP4.
-fn download_video(
-    yt_dlp_path: &Path,
-    ffmpeg_path: &Path,
-    output: &Path,
-    url: &str,
-) -> Result<()> {
-    let output_template = format!("{}/%(title)s.%(ext)s", output.display());
-    info!("Downloading video from: {}", url);
-
-    let user_agent =
-        "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:91.0) Gecko/20100101 Firefox/91.0";
-    let headers = vec![
-        (
-            "Accept",
-            "text/html,application/xhtml+xml,application/xml;q=0.9,image/avif,image/webp,*/*;q=0.8",
-        ),
-        ("Accept-Language", "en-US,en;q=0.5"),
-        ("Accept-Encoding", "gzip, deflate, br"),
-        ("Connection", "keep-alive"),
-        ("Upgrade-Insecure-Requests", "1"),
-    ];
-
-    let mut cmd = Command::new(yt_dlp_path);
-    cmd.args(&[
-        "-f",
-        "bestvideo[height=720]+bestaudio/best[height=720]",
-        "-c", // resume downloads
-        "--merge-output-format",
-        "mp4", // force MP4 output
-        "-o",
-        &output_template,
-        "--ffmpeg-location",
-        ffmpeg_path.to_str().unwrap(),
-        "--user-agent",
-        user_agent,
-        "--newline",
-    ]);
-    for (key, value) in headers {
-        cmd.args(&["--add-header", &format!("{}: {}", key, value)]);
-    }
-    cmd.arg(url);
 
-    cmd.stdout(Stdio::piped()).stderr(Stdio::piped());
-    info!("Running command: {:?}", cmd);
-
-    let mut child = cmd.spawn().with_context(|| "Failed to spawn yt-dlp process")?;
-
-    let pb = ProgressBar::new(100);
-    pb.set_style(
-        ProgressStyle::default_bar()
-            .template("{bar:40.cyan/blue} {pos:>3}%")
-            .unwrap()
-            .progress_chars("##-"),
-    );
-
-    let stdout = child.stdout.take().expect("Failed to capture stdout");
-    let stdout_thread = thread::spawn(move || {
-        let reader = BufReader::new(stdout);
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                println!("{}", line);
-            }
+/// Installs a Ctrl-C handler that stops starting new downloads and interrupts the running
+/// ones. A second Ctrl-C, or one at an interactive prompt, exits immediately. SIGTERM and
+/// SIGHUP (e.g. a closed terminal) are handled the same way, so yt-dlp isn't left running.
+fn install_ctrlc_handler() -> Result<()> {
+    ctrlc::set_handler(|| {
+        if stop_requested() || AT_PROMPT.load(Ordering::SeqCst) {
+            kill_running();
+            std::process::exit(130);
         }
-    });
+        request_stop();
+        // After SIGHUP the terminal may be gone, so a failed write is ignored.
+        let _ = writeln!(
+            io::stderr(),
+            "Stopping after current download... (press Ctrl-C again to quit now)"
+        );
+    })
+    .context("Failed to install the Ctrl-C handler")
+}
 
-    let pb_clone = pb.clone();
-    let stderr = child.stderr.take().expect("Failed to capture stderr");
-    let stderr_thread = thread::spawn(move || {
-        let reader = BufReader::new(stderr);
-        let progress_regex = Regex::new(r"\[download\]\s+(\d+\.\d+)%").unwrap();
-        for line in reader.lines() {
-            if let Ok(line) = line {
-                if let Some(caps) = progress_regex.captures(&line) {
-                    if let Some(percent_match) = caps.get(1) {
-                        if let Ok(percent) = percent_match.as_str().parse::<f64>() {
-                            pb_clone.set_position(percent.round() as u64);
-                        }
-                    }
-                } else {
-                    eprintln!("{}", line);
-                }
-            }
+/// Shows the formats available for `url` as a numbered list and asks which one to download.
+/// Returns `None` when the user just presses Enter, keeping the --quality selection.
+fn pick_format(options: &DownloadOptions, url: &str) -> Result<Option<String>> {
+    let formats = available_formats(options, url)?;
+    if formats.is_empty() {
+        return Ok(None);
+    }
+    println!("Available formats:");
+    for (i, (_, row)) in formats.iter().enumerate() {
+        println!("{:>4}) {}", i + 1, row);
+    }
+    loop {
+        let choice = prompt_user(&format!(
+            "Pick a format (1-{}, or press Enter for the default quality): ",
+            formats.len()
+        ))?;
+        if choice.is_empty() {
+            return Ok(None);
         }
-    });
-
-    let status = child.wait().with_context(|| "Failed to wait on yt-dlp process")?;
-    pb.finish_with_message("Download complete!");
+        match choice.parse::<usize>() {
+            Ok(n) if (1..=formats.len()).contains(&n) => return Ok(Some(formats[n - 1].0.clone())),
+            _ => println!("Please enter a number between 1 and {}.", formats.len()),
+        }
+    }
+}
 
-    stdout_thread.join().expect("Stdout thread panicked");
-    stderr_thread.join().expect("Stderr thread panicked");
+/// Prints a summary of yt-dlp's metadata for a video, or for each video of a playlist.
+fn print_video_info(info: &Value) {
+    if let Some(entries) = info["entries"].as_array() {
+        println!(
+            "Playlist: {} ({} videos)",
+            info["title"].as_str().unwrap_or("?"),
+            entries.len()
+        );
+        for entry in entries {
+            println!();
+            print_video_info(entry);
+        }
+        return;
+    }
+    let duration = info["duration"].as_f64().map_or_else(
+        || "?".to_string(),
+        |secs| {
+            let secs = secs as u64;
+            format!("{:02}:{:02}:{:02}", secs / 3600, secs / 60 % 60, secs % 60)
+        },
+    );
+    let upload_date = info["upload_date"].as_str().map_or_else(
+        || "?".to_string(),
+        |date| match (date.get(..4), date.get(4..6), date.get(6..8)) {
+            (Some(year), Some(month), Some(day)) => format!("{}-{}-{}", year, month, day),
+            _ => date.to_string(),
+        },
+    );
+    let mut heights: Vec<u64> = info["formats"]
+        .as_array()
+        .map(|formats| formats.iter().filter_map(|f| f["height"].as_u64()).collect())
+        .unwrap_or_default();
+    heights.sort_unstable_by(|a, b| b.cmp(a));
+    heights.dedup();
+    let resolutions: Vec<String> = heights.iter().map(|h| format!("{}p", h)).collect();
 
-    if !status.success() {
-        error!("yt-dlp failed with status: {}", status);
-        return Err(anyhow::anyhow!("yt-dlp command failed with status {}", status));
+    println!("Title:       {}", info["title"].as_str().unwrap_or("?"));
+    println!("Duration:    {}", duration);
+    println!("Uploader:    {}", info["uploader"].as_str().unwrap_or("?"));
+    println!("Upload date: {}", upload_date);
+    match info["view_count"].as_u64() {
+        Some(views) => println!("Views:       {}", views),
+        None => println!("Views:       ?"),
     }
+    println!("Resolutions: {}", resolutions.join(", "));
+}
 
-    info!("Download complete! Saved to {}", output.display());
-    info!("The downloaded video is now detached from the downloader.");
-    Ok(())
+/// Removes the partial files left by an interrupted run and exits with status 130, the
+/// usual status for a process stopped by Ctrl-C.
+fn exit_interrupted(output: &Path) -> ! {
+    if let Err(e) = remove_partial_files(output) {
+        warn!("{:#}", e);
+    }
+    std::process::exit(130);
 }
 
-fn download_video_robust(
-    yt_dlp_path: &Path,
-    ffmpeg_path: &Path,
-    output: &Path,
-    url: &str,
-    retry_delay: u64,
+/// Prints a per-URL summary of a batch with totals over its `elapsed` time and, if
+/// `report` is set, writes it there as JSON. The summary goes to stderr when `to_stderr`
+/// is set so it doesn't mix with JSON events. With `dry_run`, URLs are counted as planned.
+fn report_batch(
+    results: &[BatchResult],
+    elapsed: Duration,
+    report: Option<&Path>,
+    to_stderr: bool,
+    dry_run: bool,
 ) -> Result<()> {
-    loop {
-        match download_video(yt_dlp_path, ffmpeg_path, output, url) {
-            Ok(_) => {
-                info!("Download completed successfully.");
-                break;
-            }
-            Err(e) => {
-                error!(
-                    "Download encountered an error: {:?}. Retrying in {} seconds...",
-                    e, retry_delay
-                );
-                thread::sleep(Duration::from_secs(retry_delay));
-                info!("Resuming download...");
+    let mut summary = String::from("\nSummary:\n");
+    for result in results {
+        match &result.error {
+            None if dry_run => summary.push_str(&format!("  - {} (planned)\n", result.url)),
+            None => {
+                summary.push_str(&format!("  ✓ {} ({:.0}s)\n", result.url, result.elapsed_secs));
+                for file in &result.files {
+                    let size = file.bytes.map_or_else(|| "?".to_string(), format_size);
+                    summary.push_str(&format!(
+                        "      {:<10} {:>10}  {}\n",
+                        file.status(),
+                        size,
+                        file.path.display()
+                    ));
+                }
             }
+            Some(e) => summary.push_str(&format!("  ✗ {} ({})\n", result.url, e)),
         }
     }
+    let failed: Vec<&str> = results
+        .iter()
+        .filter(|r| !r.success)
+        .map(|r| r.url.as_str())
+        .collect();
+    let files: Vec<&DownloadOutcome> = results.iter().flat_map(|r| &r.files).collect();
+    let skipped = files.iter().filter(|file| file.skipped).count();
+    // Skipped files weren't transferred, so they don't count towards the speed.
+    let downloaded: u64 = files
+        .iter()
+        .filter(|file| !file.skipped)
+        .filter_map(|file| file.bytes)
+        .sum();
+    let total: u64 = files.iter().filter_map(|file| file.bytes).sum();
+    let secs = elapsed.as_secs_f64();
+    let speed = if secs > 0.0 { (downloaded as f64 / secs) as u64 } else { 0 };
+    summary.push_str(&format!(
+        "{} {}, {} failed.\n{} file(s) ({} already present), {} in {} ({}/s on average).",
+        results.len() - failed.len(),
+        completed_label(dry_run),
+        failed.len(),
+        files.len(),
+        skipped,
+        format_size(total),
+        humantime::format_duration(Duration::from_secs(elapsed.as_secs())),
+        format_size(speed)
+    ));
+    if !failed.is_empty() {
+        summary.push_str("\nFailed URLs:");
+        for url in &failed {
+            summary.push_str(&format!("\n  {}", url));
+        }
+    }
+    if to_stderr {
+        eprintln!("{}", summary);
+    } else {
+        println!("{}", summary);
+    }
+
+    if let Some(path) = report {
+        let json = serde_json::to_string_pretty(results)
+            .context("Failed to serialize the batch report")?;
+        std::fs::write(path, json)
+            .with_context(|| format!("Failed to write batch report to {}", path.display()))?;
+        info!("Wrote batch report to {}", path.display());
+    }
     Ok(())
 }
 
+/// How a batch summary describes the URLs that didn't fail; a dry run downloads nothing.
+fn completed_label(dry_run: bool) -> &'static str {
+    if dry_run {
+        "planned"
+    } else {
+        "succeeded"
+    }
+}
+
+/// Shows a desktop notification summarizing a finished batch, or logs the summary if no
+/// notification service is available (e.g. over SSH or without a notification daemon).
+fn notify_batch(results: &[BatchResult], elapsed: Duration, dry_run: bool) {
+    let failed = results.iter().filter(|r| !r.success).count();
+    let elapsed = humantime::format_duration(Duration::from_secs(elapsed.as_secs()));
+    let body = format!(
+        "{} {}, {} failed in {}.",
+        results.len() - failed,
+        completed_label(dry_run),
+        failed,
+        elapsed
+    );
+    let shown = notify_rust::Notification::new()
+        .summary("YouTube downloader: batch finished")
+        .body(&body)
+        .show();
+    if let Err(e) = shown {
+        warn!("Could not show a desktop notification ({}). Batch finished: {}", e, body);
+    }
+}
+
 /// Returns the directory of the current executable.
 fn get_exe_dir() -> PathBuf {
     env::current_exe()
@@ -401,10 +1212,20 @@ fn get_exe_dir() -> PathBuf {
 }
 
 fn main() -> Result<()> {
-    env_logger::init();
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    logging::init(args.quiet, args.verbose, args.log_file.as_deref())?;
+    install_ctrlc_handler()?;
     let exe_dir = get_exe_dir();
 
+    let config_path = args
+        .config
+        .clone()
+        .unwrap_or_else(|| exe_dir.join("config.toml"));
+    if let Some(config) = load_config(&config_path)? {
+        apply_config(&mut args, config, &matches)?;
+    }
+
     let yt_dlp_path = if args.yt_dlp_path.is_relative() {
         exe_dir.join(&args.yt_dlp_path)
     } else {
@@ -427,42 +1248,328 @@ fn main() -> Result<()> {
         info!("Created output directory at {}", output.display());
     }
 
+    let archive = match &args.archive {
+        Some(Some(path)) if path.is_relative() => Some(exe_dir.join(path)),
+        Some(Some(path)) => Some(path.clone()),
+        Some(None) => Some(output.join("archive.txt")),
+        None => None,
+    };
+    if let Some(archive) = &archive {
+        if let Some(parent) = archive.parent() {
+            std::fs::create_dir_all(parent).with_context(|| {
+                format!("Failed to create archive directory at {}", parent.display())
+            })?;
+        }
+        // Videos skipped because they are in the archive still make yt-dlp exit with 0, so
+        // they count as successful downloads and are never retried.
+        std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(archive)
+            .with_context(|| format!("Failed to create archive file at {}", archive.display()))?;
+    }
+
     if !yt_dlp_path.exists() {
-        error!("Error: yt-dlp not found at {}", yt_dlp_path.display());
-        std::process::exit(1);
+        if args.auto_install {
+            bootstrap_yt_dlp(
+                &yt_dlp_path,
+                &args.release_channel,
+                args.proxy.as_deref(),
+                args.github_token.as_deref(),
+            )?;
+        } else {
+            error!(
+                "Error: yt-dlp not found at {} (use --auto-install to download it)",
+                yt_dlp_path.display()
+            );
+            std::process::exit(1);
+        }
     }
     if !ffmpeg_path.exists() {
         error!("Error: ffmpeg not found at {}", ffmpeg_path.display());
         std::process::exit(1);
     }
 
-    if args.update {
-        update_yt_dlp(&yt_dlp_path)?;
-        update_ffmpeg(&ffmpeg_path)?;
+    if args.verify && !ffprobe_path(&ffmpeg_path).exists() {
+        error!(
+            "Error: --verify needs ffprobe at {}",
+            ffprobe_path(&ffmpeg_path).display()
+        );
+        std::process::exit(1);
+    }
+
+    if let Some(cookies) = &args.cookies {
+        if !cookies.is_file() {
+            error!("Error: cookies file not found at {}", cookies.display());
+            std::process::exit(1);
+        }
+    }
+
+    if args.backoff && args.retry_delay > args.max_backoff {
+        error!(
+            "Error: --retry-delay ({}s) is longer than --max-retry-delay ({}s).",
+            args.retry_delay, args.max_backoff
+        );
+        std::process::exit(1);
+    }
+
+    if args.embed_subs && args.audio_only {
+        error!("Error: --embed-subs needs a video container and cannot be used with --audio-only.");
+        std::process::exit(1);
+    }
+    if args.embed_subs && args.container == "webm" {
+        error!("Error: --embed-subs cannot embed SRT subtitles into webm; use mp4 or mkv.");
+        std::process::exit(1);
+    }
+
+    // A failed update check only warns, so it never keeps the downloads from running.
+    if args.update || args.check_only {
+        let proxy = args.proxy.as_deref();
+        let token = args.github_token.as_deref();
+        let cache = ReleaseCache {
+            path: exe_dir.join("update-check.json"),
+            max_age: args.update_interval,
+            force: args.force_update_check,
+        };
+        let channel = &args.release_channel;
+        if let Err(e) =
+            update_yt_dlp(&yt_dlp_path, channel, args.check_only, proxy, token, &cache)
+        {
+            warn!("yt-dlp update check failed: {:#}", e);
+        }
+        if let Err(e) = update_ffmpeg(&ffmpeg_path, args.check_only, proxy, token, &cache) {
+            warn!("ffmpeg update check failed: {:#}", e);
+        }
+    }
+
+    // Only video downloads are merged, and the required features are those of MP4.
+    if !args.audio_only && args.container == "mp4" {
+        let missing = missing_ffmpeg_features(&ffmpeg_path);
+        if !missing.is_empty() {
+            warn!(
+                "ffmpeg at {} lacks the {}; merging video and audio into MP4 may fail.",
+                ffmpeg_path.display(),
+                missing.join(", ")
+            );
+        }
+    }
+
+    let options = DownloadOptions {
+        yt_dlp_path,
+        ffmpeg_path,
+        output,
+        output_template: match &args.date_subdirs {
+            Some(date) => date_subdir_template(&args.output_template, date, &args.date_format),
+            None => args.output_template,
+        },
+        archive,
+        restrict_filenames: args.restrict_filenames,
+        windows_filenames: args.windows_filenames,
+        allow_any_host: args.allow_any_host,
+        retry_delay: args.retry_delay,
+        max_retries: args.max_retries,
+        backoff: args.backoff,
+        max_backoff: args.max_backoff,
+        timeout: args.timeout,
+        require_free_space: args.require_free_space,
+        limit_rate: args.limit_rate,
+        live_from_start: args.live_from_start,
+        wait_for_video: args.wait_for_video,
+        concurrent_fragments: args.concurrent_fragments,
+        audio_only: args.audio_only,
+        audio_format: args.audio_format,
+        container: args.container,
+        quality: args.quality,
+        format: None,
+        format_sort: args.format_sort,
+        no_playlist: args.no_playlist,
+        playlist_items: args.playlist_items,
+        embed_metadata: args.embed_metadata,
+        write_description: args.write_description,
+        write_info_json: args.write_info_json,
+        verify: args.verify,
+        no_mtime: args.no_mtime,
+        mtime_now: args.mtime_now,
+        user_agent: match &args.user_agent {
+            None if args.no_default_headers => None,
+            user_agent => Some(resolve_user_agent(user_agent.as_deref())),
+        },
+        no_default_headers: args.no_default_headers,
+        proxy: args.proxy,
+        subs: args.subs,
+        auto_subs: args.auto_subs,
+        embed_subs: args.embed_subs,
+        sub_langs: args.sub_langs,
+        cookies: args.cookies,
+        cookies_from_browser: args.cookies_from_browser,
+        sponsorblock_remove: args.sponsorblock_remove,
+        sponsorblock_mark: args.sponsorblock_mark,
+        progress_json: args.progress_json,
+        quiet: args.quiet,
+        verbose: args.verbose >= 2,
+        dry_run: args.dry_run,
+        yt_dlp_args: args.yt_dlp_args,
+        sections: args.section,
+        exec: args.exec,
+        exec_strict: args.exec_strict,
+        skip_existing: args.skip_existing,
+        no_resume: args.no_resume,
+    };
+    if options.dry_run {
+        info!("Dry run: yt-dlp commands will be printed instead of executed.");
+    }
+    if let Some(batch_file) = &args.batch_file {
+        let urls = read_batch_file(batch_file)?;
+        info!("Read {} URL(s) from {}", urls.len(), batch_file.display());
+        args.urls.extend(urls);
+    }
+    for url in &mut args.urls {
+        log_url_kind(url, options.playlist_items.is_some());
+        *url = normalize_url(url, args.keep_playlist);
+    }
+    if args.info {
+        if args.urls.is_empty() {
+            error!("--info requires at least one URL.");
+            std::process::exit(1);
+        }
+        let mut failed = false;
+        for url in &args.urls {
+            if args.urls.len() > 1 {
+                println!("\n==> {} <==", url);
+            }
+            match check_url(url, options.allow_any_host).and_then(|()| video_info(&options, url))
+            {
+                Ok(info) => print_video_info(&info),
+                Err(e) => {
+                    error!("Failed to get info for {}: {:#}", url, e);
+                    failed = true;
+                }
+            }
+        }
+        if failed {
+            std::process::exit(1);
+        }
+        return Ok(());
+    }
+    if args.list_formats {
+        if args.urls.is_empty() {
+            error!("--list-formats requires at least one URL.");
+            std::process::exit(1);
+        }
+        let mut failed = false;
+        for url in &args.urls {
+            if args.urls.len() > 1 {
+                println!("\n==> {} <==", url);
+            }
+            if let Err(e) = check_url(url, options.allow_any_host)
+                .and_then(|()| list_formats(&options, url))
+            {
+                error!("Failed to list formats for {}: {:#}", url, e);
+                failed = true;
+            }
+        }
+        if failed {
+            std::process::exit(1);
+        }
+        return Ok(());
     }
     if args.non_interactive || !args.urls.is_empty() {
         if args.urls.is_empty() {
             error!("Non-interactive mode requires at least one URL.");
             std::process::exit(1);
         }
-        for url in args.urls {
-            if !is_valid_url(&url) {
-                error!("Invalid URL: {}", url);
-                continue;
+        let state_path = options.output.join(BATCH_STATE_FILE);
+        let state = if args.resume_batch {
+            BatchState::load(&state_path)?
+        } else {
+            BatchState::default()
+        };
+        let urls: Vec<String> = args
+            .urls
+            .into_iter()
+            .filter(|url| !state.completed.contains(url))
+            .collect();
+        if !state.completed.is_empty() {
+            info!(
+                "Resuming batch: skipping {} URL(s) completed in an earlier run.",
+                state.completed.len()
+            );
+        }
+        let state = Mutex::new(state);
+        let save_progress = |result: &BatchResult| {
+            let mut state = state.lock().unwrap();
+            if let Err(e) = state.record(result, &state_path, options.dry_run) {
+                warn!("{:#}", e);
+            }
+        };
+        let started = Instant::now();
+        let results = download_batch(&options, urls, args.concurrency, &save_progress);
+        let elapsed = started.elapsed();
+        report_batch(
+            &results,
+            elapsed,
+            args.report.as_deref(),
+            options.progress_json,
+            options.dry_run,
+        )?;
+        if args.notify {
+            notify_batch(&results, elapsed, options.dry_run);
+        }
+        if stop_requested() {
+            exit_interrupted(&options.output);
+        }
+        if results.iter().any(|r| !r.success) {
+            std::process::exit(1);
+        }
+        // A dry run leaves the state of an earlier, real run for --resume-batch.
+        if options.dry_run {
+            return Ok(());
+        }
+        if let Err(e) = std::fs::remove_file(&state_path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                warn!("Failed to remove batch state file {}: {}", state_path.display(), e);
             }
-            download_video_robust(&yt_dlp_path, &ffmpeg_path, &output, &url, args.retry_delay)?;
         }
     } else {
+        let progress = MultiProgress::new();
         loop {
-            let url = prompt_user("Enter the YouTube video URL (or type 'exit' to quit): ")?;
-            if url.eq_ignore_ascii_case("exit") {
+            let input = prompt_user("Enter the YouTube video URL (or type 'exit' to quit): ")?;
+            if input.eq_ignore_ascii_case("exit") {
                 break;
             }
-            if !is_valid_url(&url) {
-                error!("Error: Invalid URL. Please enter a valid YouTube link.");
+            let url = normalize_url(&input, args.keep_playlist);
+            if let Err(e) = check_url(&url, options.allow_any_host) {
+                error!("Error: {}. Please enter a valid YouTube link.", e);
                 continue;
             }
-            download_video_robust(&yt_dlp_path, &ffmpeg_path, &output, &url, args.retry_delay)?;
+            log_url_kind(&input, options.playlist_items.is_some());
+            // Playlists list formats per item, so they keep the --quality selection.
+            let pick = !options.audio_only
+                && !options.dry_run
+                && (options.no_playlist || !is_playlist_url(&url));
+            let format = if pick {
+                pick_format(&options, &url).unwrap_or_else(|e| {
+                    warn!("Could not list formats, using the default quality: {:#}", e);
+                    None
+                })
+            } else {
+                None
+            };
+            let url_options = DownloadOptions {
+                format,
+                ..options.clone()
+            };
+            match download_video_robust(&url_options, &url, &progress) {
+                Ok(files) => {
+                    for file in files {
+                        println!("Saved {}", file.path.display());
+                    }
+                }
+                Err(e) => error!("Failed to download {}: {}", url, e),
+            }
+            if stop_requested() {
+                exit_interrupted(&options.output);
+            }
             let again = prompt_user("Do you want to download another video? (y/n): ")?;
             if !again.eq_ignore_ascii_case("y") {
                 break;
@@ -471,3 +1578,134 @@ fn main() -> Result<()> {
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn batch_result(url: &str, success: bool) -> BatchResult {
+        BatchResult {
+            url: url.to_string(),
+            success,
+            files: Vec::new(),
+            error: None,
+            elapsed_secs: 0.0,
+        }
+    }
+
+    /// Parses `cli` as the command line and applies the config file `toml` to it.
+    fn args_with_config(cli: &[&str], toml: &str) -> Result<Args> {
+        let matches = Args::command().get_matches_from(cli);
+        let mut args = Args::from_arg_matches(&matches).unwrap();
+        apply_config(&mut args, toml::from_str(toml).unwrap(), &matches)?;
+        Ok(args)
+    }
+
+    #[test]
+    fn validates_config_values_like_flags() {
+        for toml in [
+            "timeout = 0",
+            "concurrent_fragments = 0",
+            "concurrency = 0",
+            "keep_playlist = true\nno_playlist = true",
+            "exec_strict = true",
+            "require_free_space = \"lots\"",
+            "limit_rate = \"fast\"",
+        ] {
+            assert!(args_with_config(&["ytdl"], toml).is_err(), "{}", toml);
+        }
+        let args = args_with_config(&["ytdl"], "require_free_space = \"20G\"").unwrap();
+        assert_eq!(args.require_free_space, Some(20 << 30));
+        let args = args_with_config(&["ytdl", "--exec", "true"], "exec_strict = true").unwrap();
+        assert!(args.exec_strict);
+    }
+
+    #[test]
+    fn command_line_overrides_config() {
+        let args = args_with_config(&["ytdl", "--retry-delay", "3"], "retry_delay = 30").unwrap();
+        assert_eq!(args.retry_delay, 3);
+        let args = args_with_config(&["ytdl"], "retry_delay = 30").unwrap();
+        assert_eq!(args.retry_delay, 30);
+    }
+
+    #[test]
+    fn output_template_needs_an_extension() {
+        assert!(parse_output_template("%(title)s.%(ext)s").is_ok());
+        assert!(parse_output_template("%(uploader)s/%(title)s.mp4").is_ok());
+        assert!(parse_output_template("%(title)s").is_err());
+        assert!(parse_output_template("%(uploader)s/%(title)s").is_err());
+    }
+
+    #[test]
+    fn parses_rates() {
+        assert_eq!(parse_rate("500k").unwrap(), "500K");
+        assert_eq!(parse_rate(" 1.5M ").unwrap(), "1.5M");
+        assert_eq!(parse_rate("100").unwrap(), "100");
+        for rate in ["", "0", "fast", "5MB", "-1M", "1e3"] {
+            assert!(parse_rate(rate).is_err(), "{}", rate);
+        }
+    }
+
+    #[test]
+    fn output_template_stays_in_the_output_directory() {
+        assert!(parse_output_template("videos/./%(title)s.%(ext)s").is_ok());
+        assert!(parse_output_template("/tmp/%(title)s.%(ext)s").is_err());
+        assert!(parse_output_template("../%(title)s.%(ext)s").is_err());
+        assert!(parse_output_template("videos/../../%(title)s.%(ext)s").is_err());
+    }
+
+    #[test]
+    fn parses_sections() {
+        assert_eq!(parse_section(" *90-120 ").unwrap(), "*90-120");
+        assert!(parse_section("*00:01:30-00:02:45").is_ok());
+        assert!(parse_section("*1:30.5-inf").is_ok());
+        for section in ["90-120", "*90", "*1:2:3:4-5", "*a-b", "*inf-90"] {
+            assert!(parse_section(section).is_err(), "{}", section);
+        }
+    }
+
+    #[test]
+    fn parses_sizes() {
+        assert_eq!(parse_size("512").unwrap(), 512);
+        assert_eq!(parse_size("500M").unwrap(), 500 << 20);
+        assert_eq!(parse_size("1.5g").unwrap(), 3 << 29);
+        assert!(parse_size("lots").is_err());
+    }
+
+    #[test]
+    fn builds_date_subdirectories() {
+        assert!(parse_date_format("%Y-%m-%d").is_ok());
+        assert!(parse_date_format("%Y/%m").is_ok());
+        for format in ["", "/", "%Y)s", "%Y/../x"] {
+            assert!(parse_date_format(format).is_err(), "{:?}", format);
+        }
+        assert_eq!(
+            date_subdir_template("%(title)s.%(ext)s", "upload", "%Y/%m"),
+            "%(upload_date>%Y)s/%(upload_date>%m)s/%(title)s.%(ext)s"
+        );
+        assert_eq!(
+            date_subdir_template("%(title)s.%(ext)s", "today", "%Y-%m-%d"),
+            "%(epoch>%Y-%m-%d)s/%(title)s.%(ext)s"
+        );
+    }
+
+    #[test]
+    fn records_only_successful_real_downloads() {
+        let dir = env::temp_dir().join(format!("ytdl-batch-state-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join(BATCH_STATE_FILE);
+        let mut state = BatchState::default();
+
+        state.record(&batch_result("https://youtu.be/a", true), &path, true).unwrap();
+        let dry_run_wrote = path.exists();
+        state.record(&batch_result("https://youtu.be/b", false), &path, false).unwrap();
+        let failure_wrote = path.exists();
+        state.record(&batch_result("https://youtu.be/c", true), &path, false).unwrap();
+        let saved = BatchState::load(&path).unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        assert!(!dry_run_wrote);
+        assert!(!failure_wrote);
+        assert_eq!(saved.completed, ["https://youtu.be/c"]);
+    }
+}
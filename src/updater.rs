@@ -0,0 +1,777 @@
+//! Installing and updating the yt-dlp and ffmpeg binaries from their GitHub releases.
+
+use anyhow::{Context, Result};
+use log::{error, info, warn};
+use reqwest::blocking::{Client, ClientBuilder, Response};
+use reqwest::header::{HeaderMap, HeaderValue, ACCEPT, AUTHORIZATION, USER_AGENT};
+use reqwest::Proxy;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::thread;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use xz2::read::XzDecoder;
+use zip::ZipArchive;
+
+use crate::version::parse_version;
+
+/// ffmpeg muxers and codecs needed to merge separate video and audio streams into MP4.
+const REQUIRED_FFMPEG_MUXERS: &[&str] = &["mp4"];
+const REQUIRED_FFMPEG_CODECS: &[&str] = &["aac", "h264"];
+
+/// Name of the yt-dlp release asset for this platform, used by `--auto-install`.
+const YT_DLP_ASSET_NAME: &str = if cfg!(windows) {
+    "yt-dlp.exe"
+} else if cfg!(target_os = "macos") {
+    "yt-dlp_macos"
+} else if cfg!(target_arch = "aarch64") {
+    "yt-dlp_linux_aarch64"
+} else {
+    "yt-dlp_linux"
+};
+
+/// Time limit for connecting to GitHub and for each GitHub API request.
+const API_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Number of attempts for a GitHub API request that fails with a network or server error.
+const API_ATTEMPTS: u32 = 3;
+
+/// Pause between attempts of a failed GitHub API request.
+const API_RETRY_DELAY: Duration = Duration::from_secs(2);
+
+/// yt-dlp release channels accepted by `--release-channel`.
+pub const YT_DLP_CHANNELS: &[&str] = &["stable", "nightly", "master"];
+
+/// Returns the GitHub API URL of the latest yt-dlp release on `channel`. Nightly and master
+/// builds are published in their own repositories.
+fn latest_yt_dlp_release_url(channel: &str) -> &'static str {
+    match channel {
+        "nightly" => "https://api.github.com/repos/yt-dlp/yt-dlp-nightly-builds/releases/latest",
+        "master" => "https://api.github.com/repos/yt-dlp/yt-dlp-master-builds/releases/latest",
+        _ => "https://api.github.com/repos/yt-dlp/yt-dlp/releases/latest",
+    }
+}
+
+/// GitHub API URL of the latest ffmpeg build.
+const FFMPEG_RELEASE_URL: &str = "https://api.github.com/repos/BtbN/FFmpeg-Builds/releases/latest";
+
+/// File name of the ffmpeg binary inside the release archives.
+const FFMPEG_BINARY_NAME: &str = if cfg!(windows) { "ffmpeg.exe" } else { "ffmpeg" };
+
+/// Other tools in the ffmpeg release archives, installed next to ffmpeg when present.
+/// ffprobe is needed by `--verify`.
+const FFMPEG_COMPANION_NAMES: &[&str] = if cfg!(windows) {
+    &["ffprobe.exe", "ffplay.exe"]
+} else {
+    &["ffprobe", "ffplay"]
+};
+
+/// Platform tag used in the names of the ffmpeg release assets for this OS, or `None` where
+/// BtbN publishes no builds (macOS).
+const FFMPEG_ASSET_PLATFORM: Option<&str> = if cfg!(windows) {
+    Some("win64")
+} else if cfg!(target_os = "macos") {
+    None
+} else if cfg!(target_arch = "aarch64") {
+    Some("linuxarm64")
+} else {
+    Some("linux64")
+};
+
+/// Sends all of the client's requests through `proxy`, if one is set. Otherwise reqwest
+/// falls back to the HTTP_PROXY/HTTPS_PROXY environment variables.
+fn with_proxy(builder: ClientBuilder, proxy: Option<&str>) -> Result<ClientBuilder> {
+    match proxy {
+        Some(proxy) => {
+            let proxy =
+                Proxy::all(proxy).with_context(|| format!("Invalid proxy URL '{}'", proxy))?;
+            Ok(builder.proxy(proxy))
+        }
+        None => Ok(builder),
+    }
+}
+
+/// Builds the client used for GitHub API calls and release downloads. Only connecting is
+/// time-limited here, since release assets can take a while to download. With a `token`,
+/// requests are authenticated, which raises the API rate limit from 60 to 5000 an hour.
+fn github_client(proxy: Option<&str>, token: Option<&str>) -> Result<Client> {
+    let mut headers = HeaderMap::new();
+    headers.insert(USER_AGENT, HeaderValue::from_static("RustClient/1.0"));
+    headers.insert(ACCEPT, HeaderValue::from_static("application/vnd.github.v3+json"));
+    if let Some(token) = token {
+        let mut value = HeaderValue::from_str(&format!("Bearer {}", token.trim()))
+            .context("Invalid GitHub token")?;
+        value.set_sensitive(true);
+        headers.insert(AUTHORIZATION, value);
+    }
+    Ok(with_proxy(Client::builder(), proxy)?
+        .default_headers(headers)
+        .connect_timeout(API_TIMEOUT)
+        .build()?)
+}
+
+/// Fetches a GitHub API `url`, giving each attempt `API_TIMEOUT` and retrying timeouts,
+/// connection errors and server errors up to `API_ATTEMPTS` times in total.
+fn get_api(client: &Client, url: &str) -> reqwest::Result<Response> {
+    let mut attempt = 1;
+    loop {
+        match client.get(url).timeout(API_TIMEOUT).send() {
+            Ok(resp) if resp.status().is_server_error() && attempt < API_ATTEMPTS => {
+                let status = resp.status();
+                warn!("GitHub API returned {} (attempt {}/{})", status, attempt, API_ATTEMPTS);
+            }
+            Err(e) if (e.is_timeout() || e.is_connect()) && attempt < API_ATTEMPTS => {
+                warn!("GitHub API request failed (attempt {}/{}): {}", attempt, API_ATTEMPTS, e);
+            }
+            result => return result,
+        }
+        thread::sleep(API_RETRY_DELAY);
+        attempt += 1;
+    }
+}
+
+/// Latest-release responses from the GitHub API, kept in a JSON file so that update checks
+/// within `max_age` of each other don't count against GitHub's limit of 60 requests an hour.
+pub struct ReleaseCache {
+    pub path: PathBuf,
+    pub max_age: Duration,
+    /// Ask GitHub even if a fresh response is cached; the cache is still refreshed.
+    pub force: bool,
+}
+
+/// A cached GitHub API response and when it was fetched, in seconds since the Unix epoch.
+#[derive(Serialize, Deserialize)]
+struct CachedRelease {
+    checked: u64,
+    release: Value,
+}
+
+impl ReleaseCache {
+    /// Reads the cached responses, keyed by API URL. A missing or unreadable file counts as
+    /// an empty cache.
+    fn load(&self) -> HashMap<String, CachedRelease> {
+        std::fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|json| serde_json::from_str(&json).ok())
+            .unwrap_or_default()
+    }
+
+    fn get(&self, url: &str) -> Option<Value> {
+        if self.force {
+            return None;
+        }
+        let entry = self.load().remove(url)?;
+        let age = unix_time().saturating_sub(entry.checked);
+        (age < self.max_age.as_secs()).then_some(entry.release)
+    }
+
+    fn store(&self, url: &str, release: &Value) {
+        let mut entries = self.load();
+        entries.insert(
+            url.to_string(),
+            CachedRelease {
+                checked: unix_time(),
+                release: release.clone(),
+            },
+        );
+        let result = serde_json::to_string(&entries)
+            .map_err(std::io::Error::from)
+            .and_then(|json| std::fs::write(&self.path, json));
+        if let Err(e) = result {
+            warn!("Failed to write the update check cache {}: {}", self.path.display(), e);
+        }
+    }
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// Returns the latest-release JSON of `what` at `url`, from `cache` while it is fresh.
+/// Returns `None`, after a warning, when GitHub answers with an error status.
+fn latest_release(
+    client: &Client,
+    url: &str,
+    cache: &ReleaseCache,
+    what: &str,
+) -> Result<Option<Value>> {
+    if let Some(release) = cache.get(url) {
+        info!("Using the cached {} release info from {}.", what, cache.path.display());
+        return Ok(Some(release));
+    }
+    let response = get_api(client, url)
+        .with_context(|| format!("Failed to send request to GitHub API for {}", what))?;
+    if !response.status().is_success() {
+        warn!(
+            "Failed to fetch the latest {} version info. HTTP Status: {}",
+            what,
+            response.status()
+        );
+        return Ok(None);
+    }
+    let release: Value = response
+        .json()
+        .with_context(|| format!("Failed to parse JSON from GitHub API for {}", what))?;
+    cache.store(url, &release);
+    Ok(Some(release))
+}
+
+/// Checks for updates to yt-dlp by comparing the current version with the latest release on
+/// `channel` (stable, nightly or master). With `check_only`, the result is reported but
+/// yt-dlp is left untouched.
+pub fn update_yt_dlp(
+    yt_dlp_path: &Path,
+    channel: &str,
+    check_only: bool,
+    proxy: Option<&str>,
+    token: Option<&str>,
+    cache: &ReleaseCache,
+) -> Result<()> {
+    info!("Checking for yt-dlp updates...");
+    let output = Command::new(yt_dlp_path)
+        .arg("--version")
+        .output()
+        .with_context(|| format!("Failed to execute {:?} --version", yt_dlp_path))?;
+
+    if !output.status.success() {
+        warn!("Failed to retrieve current yt-dlp version.");
+        return Ok(());
+    }
+    let current_version = String::from_utf8_lossy(&output.stdout).trim().to_string();
+    info!("Current yt-dlp version: {}", current_version);
+
+    let client = github_client(proxy, token).context("Failed to build HTTP client")?;
+    let json = match latest_release(&client, latest_yt_dlp_release_url(channel), cache, "yt-dlp")?
+    {
+        Some(json) => json,
+        None => return Ok(()),
+    };
+    let latest_version = json["tag_name"].as_str().unwrap_or("").trim().to_string();
+    if latest_version.is_empty() {
+        warn!("Could not parse the latest version info.");
+        return Ok(());
+    }
+    info!("Latest yt-dlp {} version: {}", channel, latest_version);
+
+    let need_update = if let (Some(current_parsed), Some(latest_parsed)) =
+        (parse_version(&current_version), parse_version(&latest_version))
+    {
+        current_parsed < latest_parsed
+    } else {
+        current_version != latest_version
+    };
+
+    if need_update && check_only {
+        println!(
+            "A newer yt-dlp version is available: {} -> {}",
+            current_version, latest_version
+        );
+    } else if need_update {
+        info!("A newer yt-dlp version is available. Updating yt-dlp...");
+        let mut cmd = Command::new(yt_dlp_path);
+        cmd.args(["--update-to", channel]);
+        // yt-dlp downloads its own update, so it needs the proxy too.
+        if let Some(proxy) = proxy {
+            cmd.args(["--proxy", proxy]);
+        }
+        let status = cmd
+            .status()
+            .with_context(|| format!("Failed to execute {:?} --update-to", yt_dlp_path))?;
+        if status.success() {
+            info!("yt-dlp updated successfully.");
+        } else {
+            error!("yt-dlp update failed.");
+        }
+    } else if check_only {
+        println!("yt-dlp {} is up-to-date.", current_version);
+    } else {
+        info!("The current yt-dlp is up-to-date.");
+    }
+    Ok(())
+}
+
+/// Downloads the latest yt-dlp release on `channel` for this platform to `yt_dlp_path`.
+pub fn bootstrap_yt_dlp(
+    yt_dlp_path: &Path,
+    channel: &str,
+    proxy: Option<&str>,
+    token: Option<&str>,
+) -> Result<()> {
+    info!("yt-dlp not found, downloading the latest release...");
+
+    let client =
+        github_client(proxy, token).context("Failed to build HTTP client for yt-dlp download")?;
+
+    let response = get_api(&client, latest_yt_dlp_release_url(channel))
+        .context("Failed to send request to GitHub API for yt-dlp")?;
+    if !response.status().is_success() {
+        anyhow::bail!(
+            "Failed to fetch the latest yt-dlp release info. HTTP Status: {}",
+            response.status()
+        );
+    }
+    let json: Value = response.json().context("Failed to parse JSON from GitHub API")?;
+    let download_url = json["assets"]
+        .as_array()
+        .into_iter()
+        .flatten()
+        .find(|asset| asset["name"].as_str() == Some(YT_DLP_ASSET_NAME))
+        .and_then(|asset| asset["browser_download_url"].as_str())
+        .ok_or_else(|| {
+            anyhow::anyhow!("No {} asset in the latest yt-dlp release", YT_DLP_ASSET_NAME)
+        })?;
+
+    info!("Downloading yt-dlp from {}", download_url);
+    let resp = client
+        .get(download_url)
+        .send()
+        .context("Failed to download yt-dlp")?;
+    if !resp.status().is_success() {
+        anyhow::bail!("Failed to download yt-dlp. HTTP Status: {}", resp.status());
+    }
+    let bytes = resp.bytes().context("Failed to read yt-dlp response bytes")?;
+
+    if let Some(parent) = yt_dlp_path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("Failed to create directory {}", parent.display()))?;
+    }
+    std::fs::write(yt_dlp_path, &bytes)
+        .with_context(|| format!("Failed to write yt-dlp to {:?}", yt_dlp_path))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(yt_dlp_path, std::fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("Failed to make {:?} executable", yt_dlp_path))?;
+    }
+    info!("yt-dlp installed to {}", yt_dlp_path.display());
+    Ok(())
+}
+
+/// Renames `from` over `to`. Windows refuses to replace an executable that is still in
+/// use, so the rename is retried a few times there before giving up.
+fn replace_file(from: &Path, to: &Path) -> Result<()> {
+    let mut attempts = 0;
+    loop {
+        match std::fs::rename(from, to) {
+            Ok(()) => return Ok(()),
+            Err(e) if cfg!(windows) && attempts < 5 => {
+                attempts += 1;
+                warn!(
+                    "Could not replace {:?} ({}), retrying ({}/5)...",
+                    to, e, attempts
+                );
+                thread::sleep(Duration::from_secs(1));
+            }
+            Err(e) => {
+                let _ = std::fs::remove_file(from);
+                return Err(e).with_context(|| format!("Failed to replace {:?}", to));
+            }
+        }
+    }
+}
+
+/// Looks up the published SHA-256 digest of `asset_name` in the release's checksum assets
+/// (`<asset>.sha256` or a combined `checksums.sha256` in `sha256sum` format). Returns `None`
+/// only if the release publishes no checksums; a checksum file that can't be downloaded or
+/// doesn't list the asset is an error, so verification is never skipped silently.
+fn expected_sha256(client: &Client, assets: &[Value], asset_name: &str) -> Result<Option<String>> {
+    let per_asset = format!("{}.sha256", asset_name);
+    let checksum_asset = assets.iter().find(|asset| {
+        let name = asset["name"].as_str().unwrap_or("");
+        name == per_asset || name == "checksums.sha256"
+    });
+    let url = match checksum_asset.and_then(|asset| asset["browser_download_url"].as_str()) {
+        Some(url) => url,
+        None => return Ok(None),
+    };
+
+    let resp = client
+        .get(url)
+        .send()
+        .context("Failed to download ffmpeg checksums")?;
+    if !resp.status().is_success() {
+        anyhow::bail!("Failed to download ffmpeg checksums. HTTP Status: {}", resp.status());
+    }
+    let text = resp.text().context("Failed to read ffmpeg checksums")?;
+    for line in text.lines() {
+        let mut parts = line.split_whitespace();
+        let (hash, name) = match (parts.next(), parts.next()) {
+            (Some(hash), Some(name)) => (hash, name.trim_start_matches('*')),
+            // A per-asset checksum file may contain only the digest.
+            (Some(hash), None) => (hash, asset_name),
+            _ => continue,
+        };
+        if name == asset_name {
+            return Ok(Some(hash.to_string()));
+        }
+    }
+    anyhow::bail!("The published ffmpeg checksums don't list {}", asset_name)
+}
+
+/// Checks for a newer ffmpeg build and installs it next to `ffmpeg_path`. With `check_only`,
+/// the result is reported but ffmpeg is left untouched.
+pub fn update_ffmpeg(
+    ffmpeg_path: &Path,
+    check_only: bool,
+    proxy: Option<&str>,
+    token: Option<&str>,
+    cache: &ReleaseCache,
+) -> Result<()> {
+    info!("Checking for ffmpeg updates...");
+    let platform = match FFMPEG_ASSET_PLATFORM {
+        Some(platform) => platform,
+        None => {
+            warn!("ffmpeg updates are unsupported on this platform; update ffmpeg yourself.");
+            return Ok(());
+        }
+    };
+
+    let output = Command::new(ffmpeg_path)
+        .arg("-version")
+        .output()
+        .with_context(|| format!("Failed to execute {:?} -version", ffmpeg_path))?;
+
+    if !output.status.success() {
+        warn!("Failed to retrieve current ffmpeg version.");
+        return Ok(());
+    }
+    let current_version_output = String::from_utf8_lossy(&output.stdout);
+    let current_version_line = current_version_output.lines().next().unwrap_or("");
+    let current_version = current_version_line
+        .split_whitespace()
+        .nth(2)
+        .unwrap_or("")
+        .to_string();
+    info!("Current ffmpeg version: {}", current_version);
+
+    let client =
+        github_client(proxy, token).context("Failed to build HTTP client for ffmpeg update")?;
+
+    let json = match latest_release(&client, FFMPEG_RELEASE_URL, cache, "ffmpeg")? {
+        Some(json) => json,
+        None => return Ok(()),
+    };
+    let tag_name = json["tag_name"].as_str().unwrap_or("").trim().to_string();
+    if tag_name.is_empty() {
+        warn!("Could not parse the latest ffmpeg version info.");
+        return Ok(());
+    }
+    info!("Latest ffmpeg version: {}", tag_name);
+
+    if current_version == tag_name {
+        if check_only {
+            println!("ffmpeg {} is up-to-date.", current_version);
+        } else {
+            info!("The current ffmpeg is up-to-date.");
+        }
+        return Ok(());
+    }
+    if check_only {
+        println!(
+            "A newer ffmpeg version is available: {} -> {}",
+            current_version, tag_name
+        );
+        return Ok(());
+    }
+
+    info!("A newer ffmpeg version is available. Updating ffmpeg...");
+
+    let assets = json["assets"]
+        .as_array()
+        .ok_or_else(|| anyhow::anyhow!("No assets found in ffmpeg release JSON"))?;
+    let names = assets.iter().filter_map(|asset| asset["name"].as_str());
+    let download = ffmpeg_asset_name(names, platform).and_then(|name| {
+        let asset = assets.iter().find(|asset| asset["name"].as_str() == Some(name))?;
+        let url = asset["browser_download_url"].as_str()?;
+        Some((name.to_string(), url.to_string()))
+    });
+    let (asset_name, download_url) = match download {
+        Some(download) => download,
+        None => {
+            warn!("Could not find a suitable ffmpeg update asset for {}.", platform);
+            return Ok(());
+        }
+    };
+
+    info!("Downloading ffmpeg update from {}", download_url);
+    let resp = client
+        .get(&download_url)
+        .send()
+        .context("Failed to download ffmpeg update")?;
+    if !resp.status().is_success() {
+        error!(
+            "Failed to download ffmpeg update. HTTP Status: {}",
+            resp.status()
+        );
+        return Ok(());
+    }
+
+    let bytes = resp
+        .bytes()
+        .context("Failed to read ffmpeg update response bytes")?;
+
+    match expected_sha256(&client, assets, &asset_name)? {
+        Some(expected) => {
+            let actual = format!("{:x}", Sha256::digest(&bytes));
+            if !actual.eq_ignore_ascii_case(&expected) {
+                anyhow::bail!(
+                    "Checksum mismatch for {}: expected {}, got {}. Keeping the current ffmpeg.",
+                    asset_name,
+                    expected,
+                    actual
+                );
+            }
+            info!("Verified SHA-256 checksum of {}.", asset_name);
+        }
+        None => warn!(
+            "No checksum published for {}, skipping verification.",
+            asset_name
+        ),
+    }
+
+    let mut binaries = if asset_name.to_lowercase().ends_with(".zip") {
+        binaries_from_zip(&bytes)?
+    } else {
+        binaries_from_tar_xz(&bytes)?
+    };
+    let ffmpeg_data = match binaries.remove(FFMPEG_BINARY_NAME) {
+        Some(data) => data,
+        None => {
+            warn!("{} not found in the downloaded archive.", FFMPEG_BINARY_NAME);
+            return Ok(());
+        }
+    };
+
+    // Keep the working ffmpeg until the new one has proven it runs.
+    let mut backup_path = ffmpeg_path.as_os_str().to_owned();
+    backup_path.push(".bak");
+    let backup_path = PathBuf::from(backup_path);
+    let has_backup = ffmpeg_path.exists();
+    // Plain renames here: `replace_file` deletes its source on failure, which would lose
+    // the working binary.
+    if has_backup {
+        std::fs::rename(ffmpeg_path, &backup_path)
+            .with_context(|| format!("Failed to back up {:?}", ffmpeg_path))?;
+    }
+    let installed = install_binary(&ffmpeg_data, ffmpeg_path).and_then(|()| {
+        let output = Command::new(ffmpeg_path)
+            .arg("-version")
+            .output()
+            .with_context(|| format!("Failed to execute the new {:?}", ffmpeg_path))?;
+        if !output.status.success() {
+            anyhow::bail!("The new ffmpeg failed to run: {}", output.status);
+        }
+        Ok(())
+    });
+    if let Err(e) = installed {
+        if has_backup {
+            std::fs::rename(&backup_path, ffmpeg_path).with_context(|| {
+                format!("Failed to restore the previous ffmpeg from {:?}", backup_path)
+            })?;
+            warn!("Restored the previous ffmpeg.");
+        }
+        return Err(e);
+    }
+    if has_backup {
+        if let Err(e) = std::fs::remove_file(&backup_path) {
+            warn!("Failed to remove the ffmpeg backup {:?}: {}", backup_path, e);
+        }
+    }
+
+    for name in FFMPEG_COMPANION_NAMES {
+        match binaries.get(*name) {
+            Some(data) => {
+                let path = ffmpeg_path.with_file_name(name);
+                if let Err(e) = install_binary(data, &path) {
+                    warn!("{:#}", e);
+                }
+            }
+            None => warn!("{} not found in the downloaded archive.", name),
+        }
+    }
+    info!("ffmpeg updated successfully.");
+    Ok(())
+}
+
+/// Picks the ffmpeg build for `platform` (e.g. "linux64") from the asset names of a BtbN
+/// release. Windows builds are zip archives and Linux builds .tar.xz archives; the
+/// "-shared" builds are skipped since their ffmpeg needs the libraries next to it.
+fn ffmpeg_asset_name<'a>(
+    names: impl IntoIterator<Item = &'a str>,
+    platform: &str,
+) -> Option<&'a str> {
+    let tag = format!("-{}-", platform);
+    names.into_iter().find(|name| {
+        let lower = name.to_lowercase();
+        lower.contains(&tag)
+            && !lower.contains("-shared")
+            && (lower.ends_with(".zip") || lower.ends_with(".tar.xz"))
+    })
+}
+
+/// Returns whether an archive entry named `file_name` is ffmpeg or one of its companions.
+fn is_ffmpeg_binary(file_name: &str) -> bool {
+    file_name == FFMPEG_BINARY_NAME || FFMPEG_COMPANION_NAMES.contains(&file_name)
+}
+
+/// Reads ffmpeg and its companion binaries out of a zip archive, keyed by file name. The
+/// binaries sit in a subdirectory such as ffmpeg-*/bin/, so only file names are matched.
+fn binaries_from_zip(bytes: &[u8]) -> Result<HashMap<String, Vec<u8>>> {
+    let mut zip_archive = ZipArchive::new(Cursor::new(bytes))
+        .context("Failed to open zip archive for ffmpeg update")?;
+    let mut binaries = HashMap::new();
+    for i in 0..zip_archive.len() {
+        let mut file = zip_archive
+            .by_index(i)
+            .context("Failed to access file in zip archive")?;
+        let name = file.name().to_string();
+        let file_name = name.rsplit('/').next().unwrap_or(&name).to_lowercase();
+        if is_ffmpeg_binary(&file_name) {
+            let mut buf = Vec::new();
+            file.read_to_end(&mut buf)
+                .with_context(|| format!("Failed to read {} from zip archive", name))?;
+            binaries.insert(file_name, buf);
+        }
+    }
+    Ok(binaries)
+}
+
+/// Like `binaries_from_zip`, for the .tar.xz archives of the Linux builds.
+fn binaries_from_tar_xz(bytes: &[u8]) -> Result<HashMap<String, Vec<u8>>> {
+    let mut tar_archive = tar::Archive::new(XzDecoder::new(bytes));
+    let entries = tar_archive
+        .entries()
+        .context("Failed to open tar.xz archive for ffmpeg update")?;
+    let mut binaries = HashMap::new();
+    for entry in entries {
+        let mut entry = entry.context("Failed to access file in tar.xz archive")?;
+        let path = entry.path().context("Invalid path in tar.xz archive")?;
+        let file_name = match path.file_name() {
+            Some(name) => name.to_string_lossy().to_lowercase(),
+            None => continue,
+        };
+        if entry.header().entry_type().is_file() && is_ffmpeg_binary(&file_name) {
+            let mut buf = Vec::new();
+            entry
+                .read_to_end(&mut buf)
+                .with_context(|| format!("Failed to read {} from tar.xz archive", file_name))?;
+            binaries.insert(file_name, buf);
+        }
+    }
+    Ok(binaries)
+}
+
+/// Installs an executable at `path`. It is written next to the target and renamed over it,
+/// so an interrupted update never leaves a half-written binary behind.
+fn install_binary(data: &[u8], path: &Path) -> Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let tmp_path = PathBuf::from(tmp_path);
+    std::fs::write(&tmp_path, data).with_context(|| format!("Failed to write {:?}", tmp_path))?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(&tmp_path, std::fs::Permissions::from_mode(0o755))
+            .with_context(|| format!("Failed to make {:?} executable", tmp_path))?;
+    }
+    replace_file(&tmp_path, path)
+}
+
+/// Returns the names listed by `ffmpeg -hide_banner <flag>` (e.g. `-muxers` or `-codecs`),
+/// whose tables put the name in the second column after a `--` separator line.
+fn ffmpeg_names(ffmpeg_path: &Path, flag: &str) -> Result<Vec<String>> {
+    let output = Command::new(ffmpeg_path)
+        .args(["-hide_banner", flag])
+        .output()
+        .with_context(|| format!("Failed to execute {:?} {}", ffmpeg_path, flag))?;
+    let stdout = String::from_utf8_lossy(&output.stdout);
+    Ok(stdout
+        .lines()
+        .skip_while(|line| !line.trim().starts_with("--"))
+        .skip(1)
+        .filter_map(|line| line.split_whitespace().nth(1))
+        .flat_map(|names| names.split(','))
+        .map(str::to_string)
+        .collect())
+}
+
+/// Returns the muxers and codecs needed for merging that the ffmpeg at `ffmpeg_path`
+/// lacks. If ffmpeg cannot be queried, nothing is reported missing.
+pub fn missing_ffmpeg_features(ffmpeg_path: &Path) -> Vec<String> {
+    let lists = ffmpeg_names(ffmpeg_path, "-muxers")
+        .and_then(|muxers| Ok((muxers, ffmpeg_names(ffmpeg_path, "-codecs")?)));
+    let (muxers, codecs) = match lists {
+        Ok(lists) => lists,
+        Err(e) => {
+            warn!("Could not check ffmpeg's features: {:#}", e);
+            return Vec::new();
+        }
+    };
+    let missing_muxers = REQUIRED_FFMPEG_MUXERS
+        .iter()
+        .filter(|name| !muxers.iter().any(|m| m == *name))
+        .map(|name| format!("{} muxer", name));
+    let missing_codecs = REQUIRED_FFMPEG_CODECS
+        .iter()
+        .filter(|name| !codecs.iter().any(|c| c == *name))
+        .map(|name| format!("{} codec", name));
+    missing_muxers.chain(missing_codecs).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use xz2::write::XzEncoder;
+
+    /// Asset names from a BtbN/FFmpeg-Builds "latest" release.
+    const BTBN_ASSETS: &[&str] = &[
+        "checksums.sha256",
+        "ffmpeg-master-latest-linux64-gpl-shared.tar.xz",
+        "ffmpeg-master-latest-linux64-gpl.tar.xz",
+        "ffmpeg-master-latest-linuxarm64-gpl-shared.tar.xz",
+        "ffmpeg-master-latest-linuxarm64-gpl.tar.xz",
+        "ffmpeg-master-latest-win64-gpl-shared.zip",
+        "ffmpeg-master-latest-win64-gpl.zip",
+        "ffmpeg-master-latest-winarm64-gpl.zip",
+    ];
+
+    #[test]
+    fn picks_the_static_build_for_each_platform() {
+        let pick = |platform| ffmpeg_asset_name(BTBN_ASSETS.iter().copied(), platform);
+        assert_eq!(pick("win64"), Some("ffmpeg-master-latest-win64-gpl.zip"));
+        assert_eq!(pick("linux64"), Some("ffmpeg-master-latest-linux64-gpl.tar.xz"));
+        assert_eq!(pick("linuxarm64"), Some("ffmpeg-master-latest-linuxarm64-gpl.tar.xz"));
+        assert_eq!(pick("osx64"), None);
+    }
+
+    #[test]
+    fn extracts_binaries_from_tar_xz() {
+        let mut builder = tar::Builder::new(XzEncoder::new(Vec::new(), 6));
+        let dir = "ffmpeg-master-latest-linux64-gpl";
+        let files = [
+            (format!("{}/bin/{}", dir, FFMPEG_BINARY_NAME), "ffmpeg"),
+            (format!("{}/bin/{}", dir, FFMPEG_COMPANION_NAMES[0]), "probe"),
+            (format!("{}/LICENSE.txt", dir), "license"),
+        ];
+        for (path, data) in &files {
+            let mut header = tar::Header::new_gnu();
+            header.set_size(data.len() as u64);
+            header.set_mode(0o755);
+            header.set_cksum();
+            builder.append_data(&mut header, path, data.as_bytes()).unwrap();
+        }
+        let bytes = builder.into_inner().unwrap().finish().unwrap();
+
+        let binaries = binaries_from_tar_xz(&bytes).unwrap();
+        assert_eq!(binaries.len(), 2);
+        assert_eq!(binaries[FFMPEG_BINARY_NAME], b"ffmpeg");
+        assert_eq!(binaries[FFMPEG_COMPANION_NAMES[0]], b"probe");
+    }
+}
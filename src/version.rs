@@ -0,0 +1,58 @@
+//! Parsing of yt-dlp's date-based version numbers.
+
+use regex::Regex;
+
+/// Parses a version string in the "YYYY.MM.DD" format with an optional fourth build
+/// component, as in nightly versions like "2024.03.10.232910". Trailing suffixes such as
+/// "-nightly" are ignored and a missing build component counts as 0.
+pub fn parse_version(s: &str) -> Option<(u32, u32, u32, u64)> {
+    let re = Regex::new(r"^\s*(\d{4})\.(\d{1,2})\.(\d{1,2})(?:\.(\d+))?").ok()?;
+    let caps = re.captures(s)?;
+    let year = caps.get(1)?.as_str().parse::<u32>().ok()?;
+    let month = caps.get(2)?.as_str().parse::<u32>().ok()?;
+    let day = caps.get(3)?.as_str().parse::<u32>().ok()?;
+    let build = match caps.get(4) {
+        Some(build) => build.as_str().parse::<u64>().ok()?,
+        None => 0,
+    };
+    Some((year, month, day, build))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_release_versions() {
+        assert_eq!(parse_version("2024.03.10"), Some((2024, 3, 10, 0)));
+        assert_eq!(parse_version("2023.7.6"), Some((2023, 7, 6, 0)));
+        assert_eq!(parse_version("  2024.03.10\n"), Some((2024, 3, 10, 0)));
+    }
+
+    #[test]
+    fn parses_nightly_versions() {
+        assert_eq!(parse_version("2024.03.10.232910"), Some((2024, 3, 10, 232910)));
+        assert_eq!(parse_version("2023.12.30-nightly"), Some((2023, 12, 30, 0)));
+        assert_eq!(
+            parse_version("2024.03.10.232910 from yt-dlp/yt-dlp-nightly-builds"),
+            Some((2024, 3, 10, 232910))
+        );
+    }
+
+    #[test]
+    fn rejects_other_strings() {
+        for s in ["", "nightly", "v2024.03.10", "24.03.10"] {
+            assert_eq!(parse_version(s), None, "{}", s);
+        }
+    }
+
+    #[test]
+    fn orders_builds_of_the_same_day() {
+        let release = parse_version("2024.03.10").unwrap();
+        let nightly = parse_version("2024.03.10.123456").unwrap();
+        let later_nightly = parse_version("2024.03.10.232910").unwrap();
+        assert!(release < nightly);
+        assert!(nightly < later_nightly);
+        assert!(later_nightly < parse_version("2024.3.11").unwrap());
+    }
+}